@@ -3,7 +3,7 @@ use std::env;
 const XCB_IMDKIT_SRC: &[&str] = &[
     "parser.c",
     "ximproto.c",
-    // "imdkit.c", // currently unused as this crate only implements the client
+    "imdkit.c",
     "protocolhandler.c",
     "message.c",
     "common.c",
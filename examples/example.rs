@@ -43,7 +43,8 @@ fn main() {
         screen_default_nbr,
         InputStyle::PREEDIT_CALLBACKS,
         None,
-    );
+    )
+    .expect("failed to connect to the IME server");
     ime.set_commit_string_cb(|win, input| println!("Win {:?}, got: {}", win, input));
     ime.set_forward_event_cb(|win, e| {
         eprintln!("win={:?} {:?}", win, e);
@@ -54,7 +55,16 @@ fn main() {
 
     let mut wins = vec![];
     for _ in 0..3 {
-        wins.push(create_window(connection.clone(), &screen));
+        let win = create_window(connection.clone(), &screen);
+        match ime.create_ic(win, InputStyle::PREEDIT_CALLBACKS) {
+            Ok(ic) => {
+                if let Some(err) = ic.error() {
+                    eprintln!("input context not created yet: {}", err);
+                }
+            }
+            Err(err) => eprintln!("failed to create input context: {}", err),
+        }
+        wins.push(win);
     }
 
     let mut focus_win = wins[0];
@@ -64,10 +74,14 @@ fn main() {
         match &event {
             Event::X(xcb::x::Event::FocusIn(event)) => {
                 focus_win = event.event();
-                ime.update_pos(focus_win, 0, 0);
+                if let Err(err) = ime.update_pos(focus_win, 0, 0) {
+                    eprintln!("failed to update position: {}", err);
+                }
             }
             Event::X(xcb::x::Event::ConfigureNotify(_)) => {
-                ime.update_pos(focus_win, 0, 0);
+                if let Err(err) = ime.update_pos(focus_win, 0, 0) {
+                    eprintln!("failed to update position: {}", err);
+                }
             }
             _ => {}
         }
@@ -0,0 +1,139 @@
+use xcb::x::{KeyButMask, KeyPressEvent, KeyReleaseEvent, Window};
+use xcb::XidNew;
+
+use bitflags::bitflags;
+
+use crate::clib::*;
+use crate::{Commit, InputFeedback, PreeditCaret, StatusInfo, XimError, XCB_KEY_PRESS};
+
+/// Event of the IME, the owned counterpart of the callbacks of [`ImeClient`].
+///
+/// Each variant corresponds to the callback of the same name, see there for details. Events are
+/// only queued if enabled by [`ImeClient::set_event_queue`] or if the [`ImeClient`] is driven by
+/// an `ImeEventStream`.
+///
+/// [`ImeClient`]: crate::ImeClient
+/// [`ImeClient::set_event_queue`]: crate::ImeClient::set_event_queue
+#[derive(Debug)]
+pub enum ImeEvent {
+    /// Input composition is done, see [`ImeClient::set_commit_cb`].
+    ///
+    /// [`ImeClient::set_commit_cb`]: crate::ImeClient::set_commit_cb
+    Commit(Window, Commit),
+    /// Key event the IME did not handle, see [`ImeClient::set_forward_event_cb`].
+    ///
+    /// [`ImeClient::set_forward_event_cb`]: crate::ImeClient::set_forward_event_cb
+    ForwardedKey(Window, xcb::Event),
+    /// Input composition has started, see [`ImeClient::set_preedit_start_cb`].
+    ///
+    /// [`ImeClient::set_preedit_start_cb`]: crate::ImeClient::set_preedit_start_cb
+    PreeditStart(Window),
+    /// The preedit text has changed, see [`ImeClient::set_preedit_draw_cb`].
+    ///
+    /// [`ImeClient::set_preedit_draw_cb`]: crate::ImeClient::set_preedit_draw_cb
+    PreeditDraw(Window, PreeditUpdate),
+    /// The caret within the preedit text should be moved, see
+    /// [`ImeClient::set_preedit_caret_cb`].
+    ///
+    /// [`ImeClient::set_preedit_caret_cb`]: crate::ImeClient::set_preedit_caret_cb
+    PreeditCaret(Window, PreeditCaret),
+    /// Input composition has ended, see [`ImeClient::set_preedit_done_cb`].
+    ///
+    /// [`ImeClient::set_preedit_done_cb`]: crate::ImeClient::set_preedit_done_cb
+    PreeditDone(Window),
+    /// The status area should be shown, see [`ImeClient::set_status_start_cb`].
+    ///
+    /// [`ImeClient::set_status_start_cb`]: crate::ImeClient::set_status_start_cb
+    StatusStart(Window),
+    /// The status has changed, see [`ImeClient::set_status_draw_cb`].
+    ///
+    /// [`ImeClient::set_status_draw_cb`]: crate::ImeClient::set_status_draw_cb
+    StatusDraw(Window, StatusInfo),
+    /// The status area should be hidden, see [`ImeClient::set_status_done_cb`].
+    ///
+    /// [`ImeClient::set_status_done_cb`]: crate::ImeClient::set_status_done_cb
+    StatusDone(Window),
+    /// The geometry of the areas of the IME should be negotiated again, see
+    /// [`ImeClient::set_geometry_cb`].
+    ///
+    /// [`ImeClient::set_geometry_cb`]: crate::ImeClient::set_geometry_cb
+    Geometry(Window),
+    /// The IME has been turned on or off, see [`ImeClient::set_ime_active_cb`].
+    ///
+    /// [`ImeClient::set_ime_active_cb`]: crate::ImeClient::set_ime_active_cb
+    ImeActive(Window, bool),
+    /// The connection to the IME server has been lost, see
+    /// [`ImeClient::set_disconnected_cb`].
+    ///
+    /// [`ImeClient::set_disconnected_cb`]: crate::ImeClient::set_disconnected_cb
+    Disconnected,
+    /// The connection to the IME server has been reestablished, see
+    /// [`ImeClient::set_reconnected_cb`].
+    ///
+    /// [`ImeClient::set_reconnected_cb`]: crate::ImeClient::set_reconnected_cb
+    Reconnected,
+    /// The IME server replied with an error, see [`ImeClient::set_error_cb`].
+    ///
+    /// [`ImeClient::set_error_cb`]: crate::ImeClient::set_error_cb
+    Error(XimError),
+}
+
+bitflags! {
+    /// Status of a [`PreeditUpdate`].
+    pub struct PreeditStatus: u32 {
+        /// The update does not contain a string, see [`PreeditUpdate::text`].
+        const NO_STRING = 1;
+
+        /// The update does not contain feedback, see [`PreeditUpdate::feedback`].
+        const NO_FEEDBACK = 2;
+    }
+}
+
+/// Owned copy of a [`PreeditInfo`], see [`PreeditInfo::to_owned`].
+///
+/// Other than [`PreeditInfo`] it does not borrow from xcb-imdkit, so it can be kept after the
+/// callback returned and sent to other threads.
+///
+/// [`PreeditInfo`]: crate::PreeditInfo
+/// [`PreeditInfo::to_owned`]: crate::PreeditInfo::to_owned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreeditUpdate {
+    /// What the update contains.
+    pub status: PreeditStatus,
+    /// Cursor offset within the currently edited text in characters.
+    pub caret: u32,
+    /// Starting change position.
+    pub chg_first: u32,
+    /// Length of the change counting characters.
+    pub chg_length: u32,
+    /// Text replacing the changed range.
+    pub text: String,
+    /// Feedback information to each character of `text`.
+    pub feedback: Vec<InputFeedback>,
+}
+
+/// Copy a key event of xcb-imdkit into an event that owns its memory.
+pub(crate) fn key_event_to_owned(event: &xcb_key_press_event_t) -> xcb::Event {
+    macro_rules! copy {
+        ($ty:ident) => {
+            $ty::new(
+                event.detail,
+                event.time,
+                Window::new(event.root),
+                Window::new(event.event),
+                Window::new(event.child),
+                event.root_x,
+                event.root_y,
+                event.event_x,
+                event.event_y,
+                KeyButMask::from_bits_truncate(event.state as u32),
+                event.same_screen != 0,
+            )
+        };
+    }
+    if (event.response_type & 0x7f) == XCB_KEY_PRESS {
+        xcb::Event::X(xcb::x::Event::KeyPress(copy!(KeyPressEvent)))
+    } else {
+        xcb::Event::X(xcb::x::Event::KeyRelease(copy!(KeyReleaseEvent)))
+    }
+}
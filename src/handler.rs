@@ -0,0 +1,128 @@
+use xcb::x::Window;
+
+use crate::{Commit, PreeditCaret, PreeditInfo, PreeditState, StatusInfo, XimError};
+
+/// Handler of the events of an [`ImeClient`], an alternative to setting a callback per event.
+///
+/// The handler is owned by the [`ImeClient`] and receives `&mut self`, so that it can hold the
+/// state of the application the events are applied to. Every method does nothing by default. The
+/// handler is called before the callback set for the same event, see
+/// [`ImeClient::with_handler`].
+///
+/// [`ImeClient`]: crate::ImeClient
+/// [`ImeClient::with_handler`]: crate::ImeClient::with_handler
+pub trait ImeHandler {
+    /// Input composition is done, see [`ImeClient::set_commit_cb`].
+    ///
+    /// [`ImeClient::set_commit_cb`]: crate::ImeClient::set_commit_cb
+    fn commit(&mut self, win: Window, commit: &Commit) {
+        let _ = (win, commit);
+    }
+
+    /// Key event the IME did not handle, see [`ImeClient::set_forward_event_cb`].
+    ///
+    /// [`ImeClient::set_forward_event_cb`]: crate::ImeClient::set_forward_event_cb
+    fn forward_event(&mut self, win: Window, event: &xcb::Event) {
+        let _ = (win, event);
+    }
+
+    /// Input composition has started, see [`ImeClient::set_preedit_start_cb`].
+    ///
+    /// [`ImeClient::set_preedit_start_cb`]: crate::ImeClient::set_preedit_start_cb
+    fn preedit_start(&mut self, win: Window) {
+        let _ = win;
+    }
+
+    /// The preedit text has changed, see [`ImeClient::set_preedit_draw_cb`].
+    ///
+    /// [`ImeClient::set_preedit_draw_cb`]: crate::ImeClient::set_preedit_draw_cb
+    fn preedit_draw(&mut self, win: Window, info: &PreeditInfo<'_>) {
+        let _ = (win, info);
+    }
+
+    /// The caret within the preedit text should be moved, see
+    /// [`ImeClient::set_preedit_caret_cb`].
+    ///
+    /// Return the new position of the caret to report it to the IME, by default `None` to report
+    /// the caret of the [`PreeditState`] moved as requested. If a callback is set as well, its
+    /// answer is reported instead.
+    ///
+    /// [`ImeClient::set_preedit_caret_cb`]: crate::ImeClient::set_preedit_caret_cb
+    fn preedit_caret(&mut self, win: Window, caret: PreeditCaret) -> Option<u32> {
+        let _ = (win, caret);
+        None
+    }
+
+    /// The preedit text has changed, see [`ImeClient::set_preedit_state_cb`].
+    ///
+    /// [`ImeClient::set_preedit_state_cb`]: crate::ImeClient::set_preedit_state_cb
+    fn preedit_changed(&mut self, win: Window, preedit: &PreeditState) {
+        let _ = (win, preedit);
+    }
+
+    /// Input composition has ended, see [`ImeClient::set_preedit_done_cb`].
+    ///
+    /// [`ImeClient::set_preedit_done_cb`]: crate::ImeClient::set_preedit_done_cb
+    fn preedit_done(&mut self, win: Window) {
+        let _ = win;
+    }
+
+    /// The status area should be shown, see [`ImeClient::set_status_start_cb`].
+    ///
+    /// [`ImeClient::set_status_start_cb`]: crate::ImeClient::set_status_start_cb
+    fn status_start(&mut self, win: Window) {
+        let _ = win;
+    }
+
+    /// The status has changed, see [`ImeClient::set_status_draw_cb`].
+    ///
+    /// [`ImeClient::set_status_draw_cb`]: crate::ImeClient::set_status_draw_cb
+    fn status_draw(&mut self, win: Window, status: &StatusInfo) {
+        let _ = (win, status);
+    }
+
+    /// The status area should be hidden, see [`ImeClient::set_status_done_cb`].
+    ///
+    /// [`ImeClient::set_status_done_cb`]: crate::ImeClient::set_status_done_cb
+    fn status_done(&mut self, win: Window) {
+        let _ = win;
+    }
+
+    /// The geometry of the areas of the IME should be negotiated again, see
+    /// [`ImeClient::set_geometry_cb`].
+    ///
+    /// [`ImeClient::set_geometry_cb`]: crate::ImeClient::set_geometry_cb
+    fn geometry(&mut self, win: Window) {
+        let _ = win;
+    }
+
+    /// The IME has been turned on or off, see [`ImeClient::set_ime_active_cb`].
+    ///
+    /// [`ImeClient::set_ime_active_cb`]: crate::ImeClient::set_ime_active_cb
+    fn ime_active(&mut self, win: Window, active: bool) {
+        let _ = (win, active);
+    }
+
+    /// The connection to the IME server has been lost, see [`ImeClient::set_disconnected_cb`].
+    ///
+    /// [`ImeClient::set_disconnected_cb`]: crate::ImeClient::set_disconnected_cb
+    fn disconnected(&mut self) {}
+
+    /// The connection to the IME server has been reestablished, see
+    /// [`ImeClient::set_reconnected_cb`].
+    ///
+    /// [`ImeClient::set_reconnected_cb`]: crate::ImeClient::set_reconnected_cb
+    fn reconnected(&mut self) {}
+
+    /// The IME server replied with an error, see [`ImeClient::set_error_cb`].
+    ///
+    /// [`ImeClient::set_error_cb`]: crate::ImeClient::set_error_cb
+    fn error(&mut self, err: &XimError) {
+        let _ = err;
+    }
+}
+
+/// Handler that ignores every event, used by [`ImeClient::new`].
+///
+/// [`ImeClient::new`]: crate::ImeClient::new
+impl ImeHandler for () {}
@@ -1,5 +1,6 @@
 /*!
-Wrapper around [xcb-imdkit](https://github.com/fcitx/xcb-imdkit), providing an IME client.
+Wrapper around [xcb-imdkit](https://github.com/fcitx/xcb-imdkit), providing an IME client and an
+IME server.
 
 [xcb-imdkit](https://github.com/fcitx/xcb-imdkit) provides a partial implementation of the [X11
 Input Method Protocol](https://www.x.org/releases/current/doc/libX11/XIM/xim.html) using
@@ -12,10 +13,19 @@ To get started quickly, consult the examples folder.
 #[macro_use]
 extern crate lazy_static;
 
+use std::collections::hash_map::Entry;
+use std::collections::vec_deque::Drain;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fmt;
 use std::os::raw::{c_char, c_void};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use xcb::x::Window;
+use std::time::{Duration, Instant};
+use xcb::x::{
+    Atom, ClientMessageData, Colormap, EventMask, GetAtomName, GetKeyboardMapping, GetProperty,
+    InternAtom, Keysym, NotifyDetail, Pixmap, Rectangle, Window, ATOM_ANY, ATOM_ATOM,
+};
 use xcb::{Raw, Xid, XidNew};
 
 use bitflags::bitflags;
@@ -23,6 +33,27 @@ use bitflags::bitflags;
 use clib::*;
 
 mod clib;
+mod event;
+mod handler;
+mod preedit;
+mod server;
+#[cfg(feature = "tokio")]
+mod stream;
+mod values;
+
+pub use event::{ImeEvent, PreeditStatus, PreeditUpdate};
+pub use handler::ImeHandler;
+pub use preedit::PreeditState;
+pub use server::{ImeServer, ServerInputContext};
+#[cfg(feature = "tokio")]
+pub use stream::{ImeEventStream, StreamEvent};
+pub use values::{
+    AreaAttribute, AreaAttributeKind, Encoding, IcAttribute, IcAttributeKind, ImValues,
+    XimExtension,
+};
+
+use event::key_event_to_owned;
+use values::{query_names, values_from_raw, XimValue};
 
 type LogFn = dyn for<'a> FnMut(&'a str) + Send;
 
@@ -37,52 +68,229 @@ extern "C" {
 #[no_mangle]
 fn rust_log(msg: *const c_char) {
     let msg = unsafe { std::ffi::CStr::from_ptr(msg) }.to_string_lossy();
-    let msg = msg.trim();
+    log(msg.trim());
+}
+
+fn log(msg: &str) {
     if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
         logger(msg);
     }
 }
 
-extern "C" fn create_ic_callback(im: *mut xcb_xim_t, new_ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    ime.ic = Some(new_ic);
-    unsafe {
-        xcb_xim_set_ic_focus(im, new_ic);
-    }
+/// Maximum number of attributes that can be passed to a variadic function of xcb-imdkit at once.
+const MAX_XIM_ARGS: usize = 12;
+
+/// Name and pointer to the value of an attribute passed to a variadic function of xcb-imdkit.
+type XimArg = (*const c_void, *const c_void);
+
+fn xim_arg<T>(name: &'static [u8], value: &T) -> XimArg {
+    (name.as_ptr() as _, value as *const T as _)
+}
+
+/// Pad `args` with null pointers, xcb-imdkit stops reading arguments at the first null name.
+///
+/// Callers pass every attribute at most once, there are fewer distinct attributes than
+/// [`MAX_XIM_ARGS`] at each level.
+fn xim_args(args: &[XimArg]) -> [XimArg; MAX_XIM_ARGS + 1] {
+    assert!(args.len() <= MAX_XIM_ARGS, "too many XIM attributes");
+    let mut res = [(std::ptr::null(), std::ptr::null()); MAX_XIM_ARGS + 1];
+    res[..args.len()].copy_from_slice(args);
+    res
+}
+
+/// Call the variadic function `$f` with the fixed arguments `$fixed` followed by the attributes
+/// `$args`.
+macro_rules! call_variadic {
+    ($f:ident($($fixed:expr),*; $args:expr)) => {{
+        let args = xim_args($args);
+        $f($($fixed,)* args[0].0, args[0].1, args[1].0, args[1].1, args[2].0, args[2].1, args[3].0, args[3].1, args[4].0, args[4].1, args[5].0, args[5].1, args[6].0, args[6].1, args[7].0, args[7].1, args[8].0, args[8].1, args[9].0, args[9].1, args[10].0, args[10].1, args[11].0, args[11].1, args[MAX_XIM_ARGS].0)
+    }};
+}
+
+/// Maximum number of attribute names that can be queried from xcb-imdkit at once, enough to
+/// query every attribute of an input context.
+const MAX_XIM_NAMES: usize = 28;
+
+/// Pad `names` with null pointers, xcb-imdkit stops reading names at the first null one.
+fn xim_names(names: &[*const u8]) -> [*const u8; MAX_XIM_NAMES + 1] {
+    assert!(names.len() <= MAX_XIM_NAMES, "too many XIM attributes");
+    let mut res = [std::ptr::null(); MAX_XIM_NAMES + 1];
+    res[..names.len()].copy_from_slice(names);
+    res
+}
+
+/// Call the variadic function `$f` with the fixed arguments `$fixed` followed by the attribute
+/// names `$names`.
+macro_rules! call_variadic_names {
+    ($f:ident($($fixed:expr),*; $names:expr)) => {{
+        let names = xim_names($names);
+        $f($($fixed,)* names[0], names[1], names[2], names[3], names[4], names[5], names[6], names[7], names[8], names[9], names[10], names[11], names[12], names[13], names[14], names[15], names[16], names[17], names[18], names[19], names[20], names[21], names[22], names[23], names[24], names[25], names[26], names[27], names[MAX_XIM_NAMES])
+    }};
+}
+
+unsafe fn create_nested_list(im: *mut xcb_xim_t, args: &[XimArg]) -> xcb_xim_nested_list {
+    call_variadic!(xcb_xim_create_nested_list(im; args))
 }
 
-extern "C" fn open_callback(im: *mut xcb_xim_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let input_style = ime.input_style.bits();
-    let spot = xcb_point_t {
-        x: ime.pos_req.x,
-        y: ime.pos_req.y,
+extern "C" fn create_ic_callback<H: ImeHandler>(
+    im: *mut xcb_xim_t,
+    new_ic: xcb_xic_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    let win = match ime.pending_ics.pop_front() {
+        Some(win) => win,
+        None => return,
     };
-    let w = &mut ime.pos_req.win as *mut u32;
-    unsafe {
-        let nested = xcb_xim_create_nested_list(
-            im,
-            XCB_XIM_XNSpotLocation,
-            &spot,
-            std::ptr::null_mut::<c_void>(),
-        );
-        xcb_xim_create_ic(
+    let ic = match ime.ics.get_mut(&win) {
+        Some(ic) => ic,
+        None => {
+            // the input context has been destroyed while its creation was still in progress
+            ime.pending_event_masks.remove(&new_ic);
+            if new_ic != 0 {
+                unsafe { xcb_xim_destroy_ic(im, new_ic, None, std::ptr::null_mut()) };
+            }
+            return;
+        }
+    };
+    ic.is_creating = false;
+    if new_ic == 0 {
+        ic.error = Some(match ime.last_error.take() {
+            Some(err) if err.window.is_none() || err.window == Some(win) => ImeError::Server(err),
+            _ => ImeError::IcCreationFailed(win),
+        });
+        return;
+    }
+    ic.xic = Some(new_ic);
+    let (forward_mask, sync_mask) = ime
+        .pending_event_masks
+        .remove(&new_ic)
+        .unwrap_or((ime.default_forward_mask, ime.default_sync_mask));
+    ic.forward_mask = forward_mask;
+    ic.sync_mask = sync_mask;
+    if ime.focus == Some(win) {
+        unsafe {
+            xcb_xim_set_ic_focus(im, new_ic);
+        }
+    }
+    if ic.pos_req != ic.pos_cur {
+        ic.send_pos_update::<H>(im, user_data);
+    }
+}
+
+extern "C" fn open_callback<H: ImeHandler>(im: *mut xcb_xim_t, user_data: *mut c_void) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    ime.is_im_open = true;
+    ime.server_name = ime.server_atom.and_then(|atom| ime.server_name_of(atom));
+    if !ime.has_server {
+        ime.has_server = true;
+        for ic in ime.ics.values_mut() {
+            if let Some(ImeError::NoServer) = ic.error {
+                ic.error = None;
+            }
+        }
+    }
+    let (major, minor) = XimExtension::Move.codes();
+    ime.supports_ext_move = unsafe { xcb_xim_support_extension(im, major, minor) };
+    ime.is_querying_styles = unsafe {
+        xcb_xim_get_im_values(
             im,
-            Some(create_ic_callback),
+            Some(query_input_style_callback::<H>),
             user_data,
-            XCB_XIM_XNInputStyle,
-            &input_style,
-            XCB_XIM_XNClientWindow,
-            w,
-            XCB_XIM_XNFocusWindow,
-            w,
-            XCB_XIM_XNPreeditAttributes,
-            &nested,
+            XCB_XIM_XNQueryInputStyle,
             std::ptr::null_mut::<c_void>(),
-        );
-        free(nested.data as _);
+        )
+    };
+    ime.create_pending_ics();
+    if ime.is_reconnecting {
+        ime.is_reconnecting = false;
+        ime.handler.reconnected();
+        if let Some(f) = ime.callbacks.reconnected.as_mut() {
+            f();
+        }
+        ime.push_event(ImeEvent::Reconnected);
+    }
+}
+
+extern "C" fn query_input_style_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    reply: *mut xcb_im_get_im_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    ime.is_querying_styles = false;
+    if !reply.is_null() {
+        let attrs = unsafe { &(*reply).im_attribute_returned };
+        if attrs.size > 0 && !attrs.items.is_null() {
+            let attr = unsafe { &*attrs.items };
+            let value = unsafe { value_from_raw(attr.value, attr.value_length) };
+            ime.supported_styles = Some(input_styles_from_raw(value));
+        }
+    }
+    ime.create_pending_ics();
+}
+
+extern "C" fn im_values_callback<H: ImeHandler>(
+    im: *mut xcb_xim_t,
+    reply: *mut xcb_im_get_im_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    let f = match ime.pending_im_queries.pop_front() {
+        Some(f) => f,
+        None => return,
+    };
+    let mut input_styles = vec![];
+    if !reply.is_null() {
+        let attrs = unsafe { &(*reply).im_attribute_returned };
+        if attrs.size > 0 && !attrs.items.is_null() {
+            let attr = unsafe { &*attrs.items };
+            let value = unsafe { value_from_raw(attr.value, attr.value_length) };
+            input_styles = input_styles_from_raw(value);
+        }
+    }
+    let encoding = if unsafe { xcb_xim_get_encoding(im) } == _xcb_xim_encoding_t_XCB_XIM_UTF8_STRING
+    {
+        Encoding::Utf8
+    } else {
+        Encoding::CompoundText
+    };
+    let extensions = XimExtension::ALL
+        .iter()
+        .copied()
+        .filter(|ext| {
+            let (major, minor) = ext.codes();
+            unsafe { xcb_xim_support_extension(im, major, minor) }
+        })
+        .collect();
+    f(ImValues {
+        input_styles,
+        encoding,
+        extensions,
+        server_name: ime.server_name.clone(),
+        forward_event_mask: ime.default_forward_mask,
+    });
+}
+
+unsafe fn value_from_raw<'a>(value: *const u8, length: u16) -> &'a [u8] {
+    if value.is_null() {
+        return &[];
     }
-    ime.pos_cur = ime.pos_req;
+    std::slice::from_raw_parts(value, length as usize)
+}
+
+/// Decode a value of type `XIMStyles`: the number of styles as CARD16 followed by two bytes of
+/// padding and one CARD32 per style, all in the byte order of the client.
+fn input_styles_from_raw(value: &[u8]) -> Vec<InputStyle> {
+    if value.len() < 4 {
+        return vec![];
+    }
+    let count = u16::from_ne_bytes([value[0], value[1]]) as usize;
+    value[4..]
+        .chunks_exact(4)
+        .take(count)
+        .map(|v| InputStyle::from_bits_truncate(u32::from_ne_bytes([v[0], v[1], v[2], v[3]])))
+        .collect()
 }
 
 unsafe fn xim_encoding_to_utf8(
@@ -107,101 +315,426 @@ unsafe fn xim_encoding_to_utf8(
     String::from_utf8_unchecked(buf)
 }
 
-unsafe fn ime_from_user_data(user_data: *mut c_void) -> &'static mut ImeClient {
-    &mut *(user_data as *mut ImeClient)
+unsafe fn ime_from_user_data<'a, H: ImeHandler>(user_data: *mut c_void) -> &'a mut ImeClient<H> {
+    &mut *(user_data as *mut ImeClient<H>)
+}
+
+extern "C" fn disconnected_callback<H: ImeHandler>(_im: *mut xcb_xim_t, user_data: *mut c_void) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    ime.is_im_open = false;
+    ime.supports_ext_move = false;
+    ime.is_querying_styles = false;
+    ime.supported_styles = None;
+    ime.pending_ics.clear();
+    ime.pending_im_queries.clear();
+    ime.ic_attr_names.clear();
+    ime.pending_event_masks.clear();
+    ime.server_atom = None;
+    ime.server_name = None;
+    for ic in ime.ics.values_mut() {
+        ic.reset_connection_state();
+    }
+    ime.frame_buf.clear();
+    ime.last_error = None;
+    ime.default_forward_mask = DEFAULT_FORWARD_MASK;
+    ime.default_sync_mask = EventMask::empty();
+    // reopening the connection is deferred to the next call of `process_event` as xcb-imdkit is
+    // still cleaning up the old connection
+    ime.reconnect_pending = true;
+    ime.handler.disconnected();
+    if let Some(f) = ime.callbacks.disconnected.as_mut() {
+        f();
+    }
+    ime.push_event(ImeEvent::Disconnected);
+}
+
+extern "C" fn commit_string_callback<H: ImeHandler>(
+    im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    flag: u32,
+    input: *mut c_char,
+    length: u32,
+    keysym: *mut u32,
+    n_keysym: usize,
+    user_data: *mut c_void,
+) {
+    let flags = CommitFlags::from_bits_truncate(flag);
+    let text = if !input.is_null() && (flags.contains(CommitFlags::LOOKUP_CHARS) || length > 0) {
+        Some(unsafe { xim_encoding_to_utf8(im, input, length as usize) })
+    } else {
+        None
+    };
+    let keysyms = if keysym.is_null() {
+        vec![]
+    } else {
+        unsafe { std::slice::from_raw_parts(keysym, n_keysym) }.to_vec()
+    };
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    let commit = Commit {
+        text,
+        keysyms,
+        flags,
+    };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.commit(win, &commit);
+    }
+    if let Some(text) = &commit.text {
+        if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.commit_string) {
+            f(win, text);
+        }
+    }
+    ime.queue_event(ic, |win| ImeEvent::Commit(win, commit.clone()));
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.commit) {
+        f(win, commit);
+    }
+}
+
+/// Time after which key events queued for a synchronous event are forwarded even though the IME
+/// server has not answered it.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Events forwarded to the IME server unless it asks for others.
+const DEFAULT_FORWARD_MASK: EventMask = EventMask::KEY_PRESS.union(EventMask::KEY_RELEASE);
+
+fn event_type_mask(event: &xcb_key_press_event_t) -> EventMask {
+    if (event.response_type & !0x80) == XCB_KEY_PRESS {
+        EventMask::KEY_PRESS
+    } else {
+        EventMask::KEY_RELEASE
+    }
 }
 
-extern "C" fn disconnected_callback(
+extern "C" fn set_event_mask_callback<H: ImeHandler>(
     _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    forward_event_mask: u32,
+    synchronous_event_mask: u32,
     user_data: *mut c_void,
 ) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    ime.ic.take();
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    let forward_mask = EventMask::from_bits_truncate(forward_event_mask);
+    let sync_mask = EventMask::from_bits_truncate(synchronous_event_mask);
+    // an input context ID of 0 means the masks are not meant for a specific input context
+    if ic == 0 {
+        ime.default_forward_mask = forward_mask;
+        ime.default_sync_mask = sync_mask;
+        return;
+    }
+    let is_creating_ics = !ime.pending_ics.is_empty();
+    match ime.ic_by_xic(ic) {
+        Some(ic) => {
+            ic.forward_mask = forward_mask;
+            ic.sync_mask = sync_mask;
+        }
+        // the IME server may send the masks before the reply creating the input context
+        None if is_creating_ics => {
+            ime.pending_event_masks
+                .insert(ic, (forward_mask, sync_mask));
+        }
+        None => log(&format!(
+            "Received event masks for unknown input context {}.",
+            ic
+        )),
+    }
 }
 
-extern "C" fn commit_string_callback(
+extern "C" fn update_pos_callback<H: ImeHandler>(
     im: *mut xcb_xim_t,
-    _ic: xcb_xic_t,
-    _flag: u32,
-    input: *mut c_char,
-    length: u32,
-    _keysym: *mut u32,
-    _n_keysym: usize,
+    ic: xcb_xic_t,
     user_data: *mut c_void,
 ) {
-    let input = unsafe { xim_encoding_to_utf8(im, input, length as usize) };
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.commit_string.as_mut().map(|f| f(win, &input));
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(ic) = ime.ic_by_xic(ic) {
+        if ic.pos_update_queued {
+            ic.pos_update_queued = false;
+            ic.send_pos_update::<H>(im, user_data);
+        } else {
+            ic.is_processing_pos_update = false;
+        }
+    }
 }
 
-extern "C" fn update_pos_callback(_im: *mut xcb_xim_t, ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    if ime.pos_update_queued {
-        ime.pos_update_queued = false;
-        ime.send_pos_update(ic);
+extern "C" fn reset_ic_callback<H: ImeHandler>(
+    im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    reply: *mut xcb_im_reset_ic_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    let text = if reply.is_null() {
+        String::new()
     } else {
-        ime.is_processing_pos_update = false;
+        unsafe {
+            xim_encoding_to_utf8(
+                im,
+                (*reply).committed_string as _,
+                (*reply).byte_length_of_committed_string as usize,
+            )
+        }
+    };
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(ic) = ime.ic_by_xic(ic) {
+        if let Some(f) = ic.pending_resets.pop_front() {
+            f(ic.win, text);
+        }
     }
+    ime.clear_preedit(ic);
 }
 
 const XCB_KEY_PRESS: u8 = 2;
 const XCB_KEY_RELEASE: u8 = 3;
 
-extern "C" fn forward_event_callback(
+/// Wrap a key event of xcb-imdkit, the result must not be dropped as it does not own `event`.
+unsafe fn key_event_from_raw(event: *mut xcb_key_press_event_t) -> xcb::Event {
+    let pressed = ((*event).response_type & 0x7f) == XCB_KEY_PRESS;
+    let ptr = event as *const xcb::ffi::xcb_generic_event_t;
+    if pressed {
+        xcb::Event::X(xcb::x::Event::KeyPress(xcb::x::KeyPressEvent::from_raw(
+            ptr as _,
+        )))
+    } else {
+        xcb::Event::X(xcb::x::Event::KeyRelease(
+            xcb::x::KeyReleaseEvent::from_raw(ptr as _),
+        ))
+    }
+}
+
+extern "C" fn forward_event_callback<H: ImeHandler>(
     _im: *mut xcb_xim_t,
-    _ic: xcb_xic_t,
+    ic: xcb_xic_t,
     event: *mut xcb_key_press_event_t,
     user_data: *mut c_void,
 ) {
-    let pressed = unsafe { ((*event).response_type & 0x7f) == XCB_KEY_PRESS };
-    let ptr = event as *const xcb::ffi::xcb_generic_event_t;
-    let event = unsafe {
-        if pressed {
-            xcb::Event::X(xcb::x::Event::KeyPress(xcb::x::KeyPressEvent::from_raw(
-                ptr as _,
-            )))
-        } else {
-            xcb::Event::X(xcb::x::Event::KeyRelease(
-                xcb::x::KeyReleaseEvent::from_raw(ptr as _),
-            ))
-        }
-    };
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.forward_event.as_mut().map(|f| f(win, &event));
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    ime.queue_event(ic, |win| {
+        ImeEvent::ForwardedKey(win, key_event_to_owned(unsafe { &*event }))
+    });
+    let event = unsafe { key_event_from_raw(event) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.forward_event(win, &event);
+    }
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.forward_event) {
+        f(win, &event);
+    }
 
     // xcb::KeyPressEvent has a Drop impl that will free `event`, but since we don't own it, we
     // have to prevent that from happening
     std::mem::forget(event);
 }
 
-extern "C" fn preedit_start_callback(_im: *mut xcb_xim_t, _ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.preedit_start.as_mut().map(|f| f(win));
+extern "C" fn preedit_start_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.preedit_start(win);
+    }
+    ime.queue_event(ic, ImeEvent::PreeditStart);
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.preedit_start) {
+        f(win);
+    }
+    ime.clear_preedit(ic);
 }
 
-extern "C" fn preedit_draw_callback(
+extern "C" fn preedit_draw_callback<H: ImeHandler>(
     im: *mut xcb_xim_t,
-    _ic: xcb_xic_t,
+    ic: xcb_xic_t,
     frame: *mut xcb_im_preedit_draw_fr_t,
     user_data: *mut c_void,
 ) {
     let frame = unsafe { &*frame };
     let preedit_info = PreeditInfo { inner: frame, im };
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks
-        .preedit_draw
-        .as_mut()
-        .map(|f| f(win, preedit_info));
+    let update = preedit_info.to_owned();
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(ic) = ime.ic_by_xic(ic) {
+        ic.preedit.apply(&update);
+    }
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.preedit_draw(win, &preedit_info);
+    }
+    ime.queue_event(ic, |win| ImeEvent::PreeditDraw(win, update.clone()));
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.preedit_update) {
+        f(win, update);
+    }
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.preedit_draw) {
+        f(win, preedit_info);
+    }
+    ime.preedit_changed(ic);
+}
+
+extern "C" fn preedit_caret_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    frame: *mut xcb_im_preedit_caret_fr_t,
+    user_data: *mut c_void,
+) {
+    let frame = unsafe { &mut *frame };
+    let caret = PreeditCaret {
+        position: frame.position,
+        direction: CaretDirection::from_raw(frame.direction),
+        style: CaretStyle::from_raw(frame.style),
+    };
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    // xcb-imdkit answers with the position stored in the frame once the callback returns, unless
+    // the application answers itself it is the caret of the preedit text moved as requested
+    if let Some(ic) = ime.ic_by_xic(ic) {
+        ic.preedit.move_caret(caret);
+        frame.position = ic.preedit.caret();
+    }
+    let mut answer = None;
+    if let Some(win) = ime.ic_window(ic) {
+        answer = ime.handler.preedit_caret(win, caret);
+    }
+    ime.queue_event(ic, |win| ImeEvent::PreeditCaret(win, caret));
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.preedit_caret) {
+        answer = Some(f(win, caret));
+    }
+    if let Some(position) = answer {
+        frame.position = position;
+        if let Some(ic) = ime.ic_by_xic(ic) {
+            ic.preedit.set_caret(position);
+        }
+    }
+    ime.preedit_changed(ic);
+}
+
+extern "C" fn preedit_done_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.preedit_done(win);
+    }
+    ime.queue_event(ic, ImeEvent::PreeditDone);
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.preedit_done) {
+        f(win);
+    }
+    ime.clear_preedit(ic);
+}
+
+extern "C" fn status_start_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.status_start(win);
+    }
+    ime.queue_event(ic, ImeEvent::StatusStart);
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.status_start) {
+        f(win);
+    }
+}
+
+extern "C" fn status_draw_text_callback<H: ImeHandler>(
+    im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    frame: *mut xcb_im_status_draw_text_fr_t,
+    user_data: *mut c_void,
+) {
+    let frame = unsafe { &*frame };
+    let text = unsafe {
+        xim_encoding_to_utf8(
+            im,
+            frame.status_string as _,
+            frame.length_of_status_string as usize,
+        )
+    };
+    let feedback =
+        unsafe { feedback_from_raw(frame.feedback_array.items, frame.feedback_array.size) };
+    let status = StatusInfo::Text { text, feedback };
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.status_draw(win, &status);
+    }
+    ime.queue_event(ic, |win| ImeEvent::StatusDraw(win, status.clone()));
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.status_draw) {
+        f(win, status);
+    }
+}
+
+extern "C" fn status_draw_bitmap_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    frame: *mut xcb_im_status_draw_bitmap_fr_t,
+    user_data: *mut c_void,
+) {
+    let pixmap = unsafe { Pixmap::new((*frame).pixmap_data) };
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.status_draw(win, &StatusInfo::Bitmap(pixmap));
+    }
+    ime.queue_event(ic, |win| {
+        ImeEvent::StatusDraw(win, StatusInfo::Bitmap(pixmap))
+    });
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.status_draw) {
+        f(win, StatusInfo::Bitmap(pixmap));
+    }
+}
+
+extern "C" fn status_done_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.status_done(win);
+    }
+    ime.queue_event(ic, ImeEvent::StatusDone);
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.status_done) {
+        f(win);
+    }
+}
+
+extern "C" fn geometry_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    if let Some(win) = ime.ic_window(ic) {
+        ime.handler.geometry(win);
+    }
+    ime.queue_event(ic, ImeEvent::Geometry);
+    if let Some((win, f)) = ime.callback(ic, |cbs| &mut cbs.geometry) {
+        f(win);
+    }
+}
+
+extern "C" fn ic_values_callback<H: ImeHandler>(
+    _im: *mut xcb_xim_t,
+    ic: xcb_xic_t,
+    reply: *mut xcb_im_get_ic_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    let ime = unsafe { ime_from_user_data::<H>(user_data) };
+    let ic = match ime.ics.values_mut().find(|i| i.xic == Some(ic)) {
+        Some(ic) => ic,
+        None => return,
+    };
+    if let Some((kinds, f)) = ic.pending_value_queries.pop_front() {
+        let values = if reply.is_null() {
+            vec![]
+        } else {
+            unsafe { values_from_raw(&kinds, &*reply, &ime.ic_attr_names) }
+        };
+        f(ic.win, values);
+    }
 }
 
-extern "C" fn preedit_done_callback(_im: *mut xcb_xim_t, _ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.preedit_done.as_mut().map(|f| f(win));
+unsafe fn feedback_from_raw(items: *const u32, size: u32) -> Vec<InputFeedback> {
+    if items.is_null() {
+        return vec![];
+    }
+    std::slice::from_raw_parts(items, size as usize)
+        .iter()
+        .map(|&f| InputFeedback::from_bits_truncate(f))
+        .collect()
 }
 
 bitflags! {
@@ -211,11 +744,38 @@ bitflags! {
         /// final string after composition is finished using [`ImeClient::set_commit_string_cb`].
         const DEFAULT = 0;
 
+        /// The IME displays the preedit text in an area of the window given by the application.
+        const PREEDIT_AREA = _xcb_im_style_t_XCB_IM_PreeditArea;
+
         /// Enable calling of the preedit callbacks like the one set with
         /// [`ImeClient::set_preedit_draw_cb`]. This enables displaying the currently edited text
         /// inside the application and not only within the IME. The IME may stop displaying its
         /// cursor if this flag is set.
         const PREEDIT_CALLBACKS = _xcb_im_style_t_XCB_IM_PreeditCallbacks;
+
+        /// The IME displays the preedit text over the spot set with [`ImeClient::update_pos`]
+        /// (over-the-spot style).
+        const PREEDIT_POSITION = _xcb_im_style_t_XCB_IM_PreeditPosition;
+
+        /// The IME displays the preedit text in its own window (root-window style).
+        const PREEDIT_NOTHING = _xcb_im_style_t_XCB_IM_PreeditNothing;
+
+        /// The preedit text is not displayed at all.
+        const PREEDIT_NONE = _xcb_im_style_t_XCB_IM_PreeditNone;
+
+        /// The IME displays its status in an area of the window given by the application.
+        const STATUS_AREA = _xcb_im_style_t_XCB_IM_StatusArea;
+
+        /// Enable calling of the status callbacks like the one set with
+        /// [`ImeClient::set_status_draw_cb`]. This enables displaying the status of the IME, for
+        /// example the current input mode, inside the application.
+        const STATUS_CALLBACKS = _xcb_im_style_t_XCB_IM_StatusCallbacks;
+
+        /// The IME displays its status in its own window.
+        const STATUS_NOTHING = _xcb_im_style_t_XCB_IM_StatusNothing;
+
+        /// The status of the IME is not displayed at all.
+        const STATUS_NONE = _xcb_im_style_t_XCB_IM_StatusNone;
     }
 }
 
@@ -255,69 +815,742 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// [`CommitFlags`] describe what a [`Commit`] of the IME contains.
+    pub struct CommitFlags: u32 {
+        /// The IME server processed the commit synchronously.
+        const SYNCHRONOUS = 1;
+
+        /// The commit contains a string, see [`Commit::text`].
+        const LOOKUP_CHARS = 2;
+
+        /// The commit contains keysyms, see [`Commit::keysyms`].
+        const LOOKUP_KEYSYM = 4;
+
+        /// The commit contains both a string and keysyms.
+        const LOOKUP_BOTH = Self::LOOKUP_CHARS.bits | Self::LOOKUP_KEYSYM.bits;
+    }
+}
+
+/// [`Commit`] is the result of the input composition sent by the IME.
+///
+/// Some IMEs commit keys that are not used for input composition as keysyms instead of
+/// forwarding the key events, so keysyms should be handled like key presses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    /// Committed string, if [`CommitFlags::LOOKUP_CHARS`] is set.
+    pub text: Option<String>,
+    /// Committed keysyms, if [`CommitFlags::LOOKUP_KEYSYM`] is set.
+    pub keysyms: Vec<Keysym>,
+    /// What the commit contains.
+    pub flags: CommitFlags,
+}
+
 type StringCB = dyn for<'a> FnMut(Window, &'a str);
+type CommitCB = dyn FnMut(Window, Commit);
 type KeyPressCB = dyn for<'a> FnMut(Window, &'a xcb::Event);
 type PreeditDrawCB = dyn for<'a> FnMut(Window, PreeditInfo<'a>);
+type PreeditUpdateCB = dyn FnMut(Window, PreeditUpdate);
+type PreeditStateCB = dyn for<'a> FnMut(Window, &'a PreeditState);
+type PreeditCaretCB = dyn FnMut(Window, PreeditCaret) -> u32;
 type NotifyCB = dyn FnMut(Window);
+type ResetCB = dyn FnOnce(Window, String);
+type IcValuesCB = dyn FnOnce(Window, Vec<IcAttribute>);
+type ImValuesCB = dyn FnOnce(ImValues);
+type ConnectionCB = dyn FnMut();
+type ErrorCB = dyn FnMut(XimError);
+type ActiveCB = dyn FnMut(Window, bool);
+type StatusDrawCB = dyn FnMut(Window, StatusInfo);
 
 #[derive(Default)]
 struct Callbacks {
     commit_string: Option<Box<StringCB>>,
+    commit: Option<Box<CommitCB>>,
     forward_event: Option<Box<KeyPressCB>>,
     preedit_start: Option<Box<NotifyCB>>,
     preedit_draw: Option<Box<PreeditDrawCB>>,
+    preedit_update: Option<Box<PreeditUpdateCB>>,
+    preedit_state: Option<Box<PreeditStateCB>>,
+    preedit_caret: Option<Box<PreeditCaretCB>>,
     preedit_done: Option<Box<NotifyCB>>,
+    status_start: Option<Box<NotifyCB>>,
+    status_draw: Option<Box<StatusDrawCB>>,
+    status_done: Option<Box<NotifyCB>>,
+    geometry: Option<Box<NotifyCB>>,
+    disconnected: Option<Box<ConnectionCB>>,
+    reconnected: Option<Box<ConnectionCB>>,
+    error: Option<Box<ErrorCB>>,
+    ime_active: Option<Box<ActiveCB>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct ImePos {
-    win: u32,
     x: i16,
     y: i16,
 }
 
-/// [`PreeditInfo`] provides information about the text that is currently being edited by the IME.
-///
-/// Additionally it provides information about how the text has been changed.
-pub struct PreeditInfo<'a> {
-    im: *mut xcb_xim_t,
-    inner: &'a xcb_im_preedit_draw_fr_t,
+/// Direction in which the caret within the preedit text should be moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaretDirection {
+    /// Move the caret forward by one character.
+    ForwardChar = 0,
+    /// Move the caret backward by one character.
+    BackwardChar = 1,
+    /// Move the caret forward by one word.
+    ForwardWord = 2,
+    /// Move the caret backward by one word.
+    BackwardWord = 3,
+    /// Move the caret up by one line keeping the current horizontal offset.
+    CaretUp = 4,
+    /// Move the caret down by one line keeping the current horizontal offset.
+    CaretDown = 5,
+    /// Move the caret to the beginning of the next line.
+    NextLine = 6,
+    /// Move the caret to the beginning of the previous line.
+    PreviousLine = 7,
+    /// Move the caret to the beginning of the current line.
+    LineStart = 8,
+    /// Move the caret to the end of the current line.
+    LineEnd = 9,
+    /// Move the caret to the position given by [`PreeditCaret::position`].
+    AbsolutePosition = 10,
+    /// Do not move the caret, only change its style.
+    DontChange = 11,
 }
 
-impl<'a> PreeditInfo<'a> {
-    /// Status bitmask.
-    ///
-    /// - `0x01`: no string
-    /// - `0x02`: no feedback
-    ///
-    /// If no bits are set, [`text`] contains the current text of the IME.
-    ///
-    /// [`text`]: PreeditInfo::text
-    pub fn status(&self) -> u32 {
-        self.inner.status
+impl CaretDirection {
+    fn from_raw(direction: u32) -> Self {
+        match direction {
+            0 => Self::ForwardChar,
+            1 => Self::BackwardChar,
+            2 => Self::ForwardWord,
+            3 => Self::BackwardWord,
+            4 => Self::CaretUp,
+            5 => Self::CaretDown,
+            6 => Self::NextLine,
+            7 => Self::PreviousLine,
+            8 => Self::LineStart,
+            9 => Self::LineEnd,
+            10 => Self::AbsolutePosition,
+            _ => Self::DontChange,
+        }
     }
+}
 
-    /// Cursor offset within the currently edited text in characters.
-    pub fn caret(&self) -> u32 {
-        self.inner.caret
-    }
+/// Style in which the caret within the preedit text should be drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaretStyle {
+    /// The caret should not be drawn.
+    Invisible = 0,
+    /// The caret should be drawn in the primary style, usually a bar.
+    Primary = 1,
+    /// The caret should be drawn in the secondary style, usually a block.
+    Secondary = 2,
+}
 
-    /// Starting change position.
-    pub fn chg_first(&self) -> u32 {
-        self.inner.chg_first
+impl CaretStyle {
+    fn from_raw(style: u32) -> Self {
+        match style {
+            0 => Self::Invisible,
+            2 => Self::Secondary,
+            _ => Self::Primary,
+        }
     }
+}
 
-    /// Length of the change counting characters.
-    pub fn chg_length(&self) -> u32 {
-        self.inner.chg_length
-    }
+/// [`PreeditCaret`] is a request of the IME to move the caret within the preedit text without
+/// redrawing the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreeditCaret {
+    /// Position of the caret in characters, only meaningful for
+    /// [`CaretDirection::AbsolutePosition`].
+    pub position: u32,
+    /// Direction in which to move the caret.
+    pub direction: CaretDirection,
+    /// Style in which the caret should be drawn.
+    pub style: CaretStyle,
+}
 
-    /// Current text in the IME.
-    pub fn text(&self) -> String {
-        unsafe {
-            xim_encoding_to_utf8(
-                self.im,
-                self.inner.preedit_string as _,
+/// [`StatusInfo`] describes what the IME wants to be displayed in its status area.
+///
+/// The status area usually shows the current mode of the IME, for example "あ" or "A".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatusInfo {
+    /// Text to display in the status area.
+    Text {
+        /// Status text, empty if the status area should be cleared.
+        text: String,
+        /// Feedback information to each character of the status text.
+        feedback: Vec<InputFeedback>,
+    },
+    /// Pixmap to display in the status area.
+    Bitmap(Pixmap),
+}
+
+/// Size the IME would like its areas to have, see [`ImeClient::query_area_needed`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AreaNeeded {
+    /// Area needed for the preedit text, if the input style contains [`InputStyle::PREEDIT_AREA`].
+    pub preedit: Option<Rectangle>,
+    /// Area needed for the status, if the input style contains [`InputStyle::STATUS_AREA`].
+    pub status: Option<Rectangle>,
+}
+
+/// Attributes of the preedit or status area.
+///
+/// [`PreeditAttributes`] controls how the IME draws the preedit text or its status if it does so
+/// by itself, that is if the input style contains [`InputStyle::PREEDIT_POSITION`],
+/// [`InputStyle::PREEDIT_AREA`] or [`InputStyle::STATUS_AREA`]. Only attributes that are set are
+/// sent to the IME, start with [`PreeditAttributes::new`] and chain the setters for the attributes
+/// the application wants to control.
+#[derive(Debug, Clone, Default)]
+pub struct PreeditAttributes {
+    /// Area in which the IME should draw.
+    pub area: Option<Rectangle>,
+    /// Area the IME would like to use, see [`InputStyle::PREEDIT_AREA`].
+    pub area_needed: Option<Rectangle>,
+    /// Colormap to use for drawing.
+    pub colormap: Option<Colormap>,
+    /// Foreground pixel value.
+    pub foreground: Option<u32>,
+    /// Background pixel value.
+    pub background: Option<u32>,
+    /// Background pixmap.
+    pub background_pixmap: Option<Pixmap>,
+    /// Base font name list of the font set to draw text with, e.g. `"-*-*-medium-r-normal--16-*"`.
+    pub font_set: Option<String>,
+    /// Distance between two lines of text in pixels.
+    pub line_space: Option<u32>,
+}
+
+impl PreeditAttributes {
+    /// Create empty [`PreeditAttributes`] that leave all attributes to the IME.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the area in which the IME should draw.
+    pub fn area(mut self, area: Rectangle) -> Self {
+        self.area = Some(area);
+        self
+    }
+
+    /// Set the area the IME would like to use.
+    pub fn area_needed(mut self, area_needed: Rectangle) -> Self {
+        self.area_needed = Some(area_needed);
+        self
+    }
+
+    /// Set the colormap to use for drawing.
+    pub fn colormap(mut self, colormap: Colormap) -> Self {
+        self.colormap = Some(colormap);
+        self
+    }
+
+    /// Set the foreground pixel value.
+    pub fn foreground(mut self, pixel: u32) -> Self {
+        self.foreground = Some(pixel);
+        self
+    }
+
+    /// Set the background pixel value.
+    pub fn background(mut self, pixel: u32) -> Self {
+        self.background = Some(pixel);
+        self
+    }
+
+    /// Set the background pixmap.
+    pub fn background_pixmap(mut self, pixmap: Pixmap) -> Self {
+        self.background_pixmap = Some(pixmap);
+        self
+    }
+
+    /// Set the base font name list of the font set to draw text with.
+    pub fn font_set(mut self, base_font_names: &str) -> Self {
+        self.font_set = Some(base_font_names.to_owned());
+        self
+    }
+
+    /// Set the distance between two lines of text in pixels.
+    pub fn line_space(mut self, line_space: u32) -> Self {
+        self.line_space = Some(line_space);
+        self
+    }
+
+    fn check_encoding(&self) -> Result<(), ImeError> {
+        match &self.font_set {
+            Some(font_set) if font_set.contains('\0') => Err(ImeError::Encoding),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.area.is_none()
+            && self.area_needed.is_none()
+            && self.colormap.is_none()
+            && self.foreground.is_none()
+            && self.background.is_none()
+            && self.background_pixmap.is_none()
+            && self.font_set.is_none()
+            && self.line_space.is_none()
+    }
+
+    /// Create a nested list of the attributes that are set, prefixed by `spot` if given.
+    unsafe fn create_nested_list(
+        &self,
+        im: *mut xcb_xim_t,
+        spot: Option<&xcb_point_t>,
+    ) -> xcb_xim_nested_list {
+        let font_set = self
+            .font_set
+            .as_ref()
+            .and_then(|f| std::ffi::CString::new(f.as_str()).ok());
+        let mut args = vec![];
+        if let Some(spot) = spot {
+            args.push(xim_arg(XCB_XIM_XNSpotLocation, spot));
+        }
+        if let Some(area) = &self.area {
+            args.push(xim_arg(XCB_XIM_XNArea, area));
+        }
+        if let Some(area_needed) = &self.area_needed {
+            args.push(xim_arg(XCB_XIM_XNAreaNeeded, area_needed));
+        }
+        if let Some(colormap) = &self.colormap {
+            args.push(xim_arg(XCB_XIM_XNColormap, colormap));
+        }
+        if let Some(foreground) = &self.foreground {
+            args.push(xim_arg(XCB_XIM_XNForeground, foreground));
+        }
+        if let Some(background) = &self.background {
+            args.push(xim_arg(XCB_XIM_XNBackground, background));
+        }
+        if let Some(pixmap) = &self.background_pixmap {
+            args.push(xim_arg(XCB_XIM_XNBackgroundPixmap, pixmap));
+        }
+        if let Some(font_set) = &font_set {
+            args.push((XCB_XIM_XNFontSet.as_ptr() as _, font_set.as_ptr() as _));
+        }
+        if let Some(line_space) = &self.line_space {
+            args.push(xim_arg(XCB_XIM_XNLineSpace, line_space));
+        }
+        create_nested_list(im, &args)
+    }
+}
+
+/// Input context of a single window.
+///
+/// Each window that receives input through the IME gets its own [`InputContext`] so that the
+/// preedit state, the position of the IME window and the input style are kept separately for
+/// every window. Input contexts are created by [`ImeClient::create_ic`] or implicitly by
+/// [`ImeClient::update_pos`] and [`ImeClient::process_event`] for windows that do not have one
+/// yet.
+///
+/// Callbacks set on an [`InputContext`] take precedence over the ones set on the [`ImeClient`]
+/// and are only called for events of this input context.
+pub struct InputContext {
+    win: Window,
+    xic: Option<xcb_xic_t>,
+    is_creating: bool,
+    preferred_style: Option<InputStyle>,
+    input_style: InputStyle,
+    preedit_attrs: PreeditAttributes,
+    status_attrs: PreeditAttributes,
+    callbacks: Callbacks,
+    pending_resets: VecDeque<Box<ResetCB>>,
+    pending_value_queries: VecDeque<(Vec<IcAttributeKind>, Box<IcValuesCB>)>,
+    pos_cur: ImePos,
+    pos_req: ImePos,
+    is_processing_pos_update: bool,
+    pos_update_queued: bool,
+    error: Option<ImeError>,
+    is_triggered: bool,
+    forward_mask: EventMask,
+    sync_mask: EventMask,
+    awaiting_sync_since: Option<Instant>,
+    pending_events: VecDeque<xcb_key_press_event_t>,
+    preedit: PreeditState,
+}
+
+impl InputContext {
+    fn new(win: Window, preferred_style: Option<InputStyle>, input_style: InputStyle) -> Self {
+        Self {
+            win,
+            xic: None,
+            is_creating: false,
+            preferred_style,
+            input_style,
+            preedit_attrs: PreeditAttributes::default(),
+            status_attrs: PreeditAttributes::default(),
+            callbacks: Callbacks::default(),
+            pending_resets: VecDeque::new(),
+            pending_value_queries: VecDeque::new(),
+            pos_cur: ImePos { x: 0, y: 0 },
+            pos_req: ImePos { x: 0, y: 0 },
+            is_processing_pos_update: false,
+            pos_update_queued: false,
+            error: None,
+            is_triggered: false,
+            forward_mask: DEFAULT_FORWARD_MASK,
+            sync_mask: EventMask::empty(),
+            awaiting_sync_since: None,
+            pending_events: VecDeque::new(),
+            preedit: PreeditState::new(),
+        }
+    }
+
+    /// Window this input context belongs to.
+    pub fn window(&self) -> Window {
+        self.win
+    }
+
+    /// Input style the input context has been created with.
+    ///
+    /// This is the style negotiated with the IME server, see
+    /// [`ImeClient::set_preferred_input_styles`].
+    pub fn input_style(&self) -> InputStyle {
+        self.input_style
+    }
+
+    /// Last position of the IME window requested by [`ImeClient::update_pos`].
+    pub fn spot_location(&self) -> (i16, i16) {
+        (self.pos_req.x, self.pos_req.y)
+    }
+
+    /// Attributes of the preedit area set by [`ImeClient::set_preedit_attributes`].
+    pub fn preedit_attributes(&self) -> &PreeditAttributes {
+        &self.preedit_attrs
+    }
+
+    /// Attributes of the status area set by [`ImeClient::set_status_attributes`].
+    pub fn status_attributes(&self) -> &PreeditAttributes {
+        &self.status_attrs
+    }
+
+    /// Current preedit text, see [`PreeditState`].
+    pub fn preedit(&self) -> &PreeditState {
+        &self.preedit
+    }
+
+    /// Error that prevented the IME server from creating the input context.
+    ///
+    /// The creation is tried again once the connection to the IME server has been reestablished
+    /// or after the input context has been destroyed and created anew. [`ImeError::NoServer`] is
+    /// reported while the input context waits for an IME server to be started, see
+    /// [`ImeClient::new`].
+    pub fn error(&self) -> Option<&ImeError> {
+        self.error.as_ref()
+    }
+
+    /// Return `true` if the input context has been created by the IME server.
+    pub fn is_created(&self) -> bool {
+        self.xic.is_some()
+    }
+
+    /// Events the IME server wants to be forwarded to it.
+    ///
+    /// Only keypress and keyrelease events are forwarded by [`ImeClient::process_event`].
+    pub fn forward_event_mask(&self) -> EventMask {
+        self.forward_mask
+    }
+
+    /// Events the IME server processes synchronously.
+    ///
+    /// Once such an event has been forwarded, further key events are queued by
+    /// [`ImeClient::process_event`] until the IME server answered it, so that they are processed
+    /// in order.
+    pub fn synchronous_event_mask(&self) -> EventMask {
+        self.sync_mask
+    }
+
+    /// Number of key events queued while waiting for the IME server to answer a synchronous
+    /// event, see [`synchronous_event_mask`].
+    ///
+    /// The events are forwarded once the IME server has sent `XIM_SYNC_REPLY`. If it has not
+    /// answered within a second, they are forwarded by the next call of
+    /// [`ImeClient::process_event`], whatever event it is passed.
+    ///
+    /// [`synchronous_event_mask`]: InputContext::synchronous_event_mask
+    pub fn pending_events(&self) -> usize {
+        self.pending_events.len()
+    }
+
+    /// Set callback to be called once input composition is done.
+    ///
+    /// Same as [`ImeClient::set_commit_string_cb`], but only for this input context.
+    pub fn set_commit_string_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, &'a str) + 'static,
+    {
+        self.callbacks.commit_string = Some(Box::new(f));
+    }
+
+    /// Set callback to be called for everything the IME commits.
+    ///
+    /// Same as [`ImeClient::set_commit_cb`], but only for this input context.
+    pub fn set_commit_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, Commit) + 'static,
+    {
+        self.callbacks.commit = Some(Box::new(f));
+    }
+
+    /// Set callback for keypress/keyrelease events unhandled by the IME.
+    ///
+    /// Same as [`ImeClient::set_forward_event_cb`], but only for this input context.
+    pub fn set_forward_event_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, &'a xcb::Event) + 'static,
+    {
+        self.callbacks.forward_event = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME has been opened.
+    ///
+    /// Same as [`ImeClient::set_preedit_start_cb`], but only for this input context.
+    pub fn set_preedit_start_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.preedit_start = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the text whitin the IME has changed.
+    ///
+    /// Same as [`ImeClient::set_preedit_draw_cb`], but only for this input context.
+    pub fn set_preedit_draw_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, PreeditInfo<'a>) + 'static,
+    {
+        self.callbacks.preedit_draw = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the text whitin the IME has changed, with an owned copy of the
+    /// change.
+    ///
+    /// Same as [`ImeClient::set_preedit_update_cb`], but only for this input context.
+    pub fn set_preedit_update_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, PreeditUpdate) + 'static,
+    {
+        self.callbacks.preedit_update = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the preedit text has changed, with the whole text.
+    ///
+    /// Same as [`ImeClient::set_preedit_state_cb`], but only for this input context.
+    pub fn set_preedit_state_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, &'a PreeditState) + 'static,
+    {
+        self.callbacks.preedit_state = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the IME moves the caret within the preedit text.
+    ///
+    /// Same as [`ImeClient::set_preedit_caret_cb`], but only for this input context.
+    pub fn set_preedit_caret_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, PreeditCaret) -> u32 + 'static,
+    {
+        self.callbacks.preedit_caret = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME has been closed.
+    ///
+    /// Same as [`ImeClient::set_preedit_done_cb`], but only for this input context.
+    pub fn set_preedit_done_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.preedit_done = Some(Box::new(f));
+    }
+
+    /// Callback called once the status area of the IME should be shown.
+    ///
+    /// Same as [`ImeClient::set_status_start_cb`], but only for this input context.
+    pub fn set_status_start_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.status_start = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the status of the IME has changed.
+    ///
+    /// Same as [`ImeClient::set_status_draw_cb`], but only for this input context.
+    pub fn set_status_draw_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, StatusInfo) + 'static,
+    {
+        self.callbacks.status_draw = Some(Box::new(f));
+    }
+
+    /// Callback called once the status area of the IME should be hidden.
+    ///
+    /// Same as [`ImeClient::set_status_done_cb`], but only for this input context.
+    pub fn set_status_done_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.status_done = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME wants the geometry of its areas to be negotiated again.
+    ///
+    /// Same as [`ImeClient::set_geometry_cb`], but only for this input context.
+    pub fn set_geometry_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.geometry = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME is turned on or off by a trigger key.
+    ///
+    /// Same as [`ImeClient::set_ime_active_cb`], but only for this input context.
+    pub fn set_ime_active_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, bool) + 'static,
+    {
+        self.callbacks.ime_active = Some(Box::new(f));
+    }
+
+    fn reset_connection_state(&mut self) {
+        self.xic = None;
+        self.pending_resets.clear();
+        self.pending_value_queries.clear();
+        self.is_creating = false;
+        self.is_processing_pos_update = false;
+        self.pos_update_queued = false;
+        self.error = None;
+        self.is_triggered = false;
+        self.forward_mask = DEFAULT_FORWARD_MASK;
+        self.sync_mask = EventMask::empty();
+        self.awaiting_sync_since = None;
+        self.pending_events.clear();
+        self.preedit.clear();
+    }
+
+    /// Forward `event` to the IME server, or queue it if the IME server has not yet answered a
+    /// synchronous event.
+    ///
+    /// If the IME server has not answered within [`SYNC_TIMEOUT`], the queued events are
+    /// forwarded anyway so that a lost answer does not block key input for good.
+    fn forward_event(&mut self, im: *mut xcb_xim_t, mut event: xcb_key_press_event_t) {
+        let xic = match self.xic {
+            Some(xic) => xic,
+            None => return,
+        };
+        if self.awaiting_sync_since.is_some() {
+            self.pending_events.push_back(event);
+            self.release_expired_events(im);
+            return;
+        }
+        unsafe { xcb_xim_forward_event(im, xic, &mut event) };
+        if self.sync_mask.contains(event_type_mask(&event)) {
+            self.awaiting_sync_since = Some(Instant::now());
+        }
+    }
+
+    /// Forward the queued events if the IME server has not answered within [`SYNC_TIMEOUT`].
+    fn release_expired_events(&mut self, im: *mut xcb_xim_t) {
+        match self.awaiting_sync_since {
+            Some(since) if since.elapsed() >= SYNC_TIMEOUT => {
+                log("The IME server did not answer a synchronous event, forwarding queued events.");
+                self.release_pending_events(im);
+            }
+            _ => {}
+        }
+    }
+
+    /// Forward the events queued while waiting for the IME server to answer a synchronous event.
+    fn release_pending_events(&mut self, im: *mut xcb_xim_t) {
+        self.awaiting_sync_since = None;
+        while self.awaiting_sync_since.is_none() {
+            match self.pending_events.pop_front() {
+                Some(event) => self.forward_event(im, event),
+                None => break,
+            }
+        }
+    }
+
+    fn send_pos_update<H: ImeHandler>(
+        &mut self,
+        im: *mut xcb_xim_t,
+        user_data: *mut c_void,
+    ) -> bool {
+        let ic = match self.xic {
+            Some(ic) => ic,
+            None => return false,
+        };
+        let spot = xcb_point_t {
+            x: self.pos_req.x,
+            y: self.pos_req.y,
+        };
+        let sent = unsafe {
+            let nested = create_nested_list(im, &[xim_arg(XCB_XIM_XNSpotLocation, &spot)]);
+            let sent = xcb_xim_set_ic_values(
+                im,
+                ic,
+                Some(update_pos_callback::<H>),
+                user_data,
+                XCB_XIM_XNPreeditAttributes,
+                &nested,
+                std::ptr::null_mut::<c_void>(),
+            );
+            free(nested.data as _);
+            sent
+        };
+        if sent {
+            self.is_processing_pos_update = true;
+            self.pos_cur = self.pos_req;
+        }
+        sent
+    }
+}
+
+/// [`PreeditInfo`] provides information about the text that is currently being edited by the IME.
+///
+/// Additionally it provides information about how the text has been changed.
+pub struct PreeditInfo<'a> {
+    im: *mut xcb_xim_t,
+    inner: &'a xcb_im_preedit_draw_fr_t,
+}
+
+impl<'a> PreeditInfo<'a> {
+    /// Status bitmask.
+    ///
+    /// - `0x01`: no string
+    /// - `0x02`: no feedback
+    ///
+    /// If no bits are set, [`text`] contains the current text of the IME.
+    ///
+    /// [`text`]: PreeditInfo::text
+    pub fn status(&self) -> u32 {
+        self.inner.status
+    }
+
+    /// Cursor offset within the currently edited text in characters.
+    pub fn caret(&self) -> u32 {
+        self.inner.caret
+    }
+
+    /// Starting change position.
+    pub fn chg_first(&self) -> u32 {
+        self.inner.chg_first
+    }
+
+    /// Length of the change counting characters.
+    pub fn chg_length(&self) -> u32 {
+        self.inner.chg_length
+    }
+
+    /// Current text in the IME.
+    pub fn text(&self) -> String {
+        unsafe {
+            xim_encoding_to_utf8(
+                self.im,
+                self.inner.preedit_string as _,
                 self.inner.length_of_preedit_string as usize,
             )
         }
@@ -333,6 +1566,24 @@ impl<'a> PreeditInfo<'a> {
             )
         }
     }
+
+    /// Copy the information into a [`PreeditUpdate`], which is independent of the callback and
+    /// can be sent to other threads.
+    pub fn to_owned(&self) -> PreeditUpdate {
+        PreeditUpdate {
+            status: PreeditStatus::from_bits_truncate(self.status()),
+            caret: self.caret(),
+            chg_first: self.chg_first(),
+            chg_length: self.chg_length(),
+            text: self.text(),
+            feedback: unsafe {
+                feedback_from_raw(
+                    self.inner.feedback_array.items,
+                    self.inner.feedback_array.size,
+                )
+            },
+        }
+    }
 }
 
 impl<'a> std::fmt::Debug for PreeditInfo<'a> {
@@ -348,23 +1599,348 @@ impl<'a> std::fmt::Debug for PreeditInfo<'a> {
     }
 }
 
-/// Input Method Editor (IME) client.
-///
-/// [`ImeClient`] represents one instance of an Input Method Editor client. It provides callbacks for
+/// Error codes of the XIM protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XimErrorCode {
+    /// The IME server ran out of memory.
+    BadAlloc,
+    /// The input style is not supported.
+    BadStyle,
+    /// The client window is invalid.
+    BadClientWindow,
+    /// The focus window is invalid.
+    BadFocusWindow,
+    /// The preedit or status area is invalid.
+    BadArea,
+    /// The spot location is invalid.
+    BadSpotLocation,
+    /// The colormap is invalid.
+    BadColormap,
+    /// An atom is invalid.
+    BadAtom,
+    /// A pixel value is invalid.
+    BadPixel,
+    /// A pixmap is invalid.
+    BadPixmap,
+    /// An attribute or font name is invalid.
+    BadName,
+    /// The cursor is invalid.
+    BadCursor,
+    /// A request violated the XIM protocol.
+    BadProtocol,
+    /// The foreground color is invalid.
+    BadForeground,
+    /// The background color is invalid.
+    BadBackground,
+    /// The locale is not supported by the IME server.
+    LocaleNotSupported,
+    /// Any other error, also used for error codes unknown to this crate.
+    BadSomething,
+}
+
+impl XimErrorCode {
+    fn from_raw(code: u16) -> Self {
+        match code {
+            1 => XimErrorCode::BadAlloc,
+            2 => XimErrorCode::BadStyle,
+            3 => XimErrorCode::BadClientWindow,
+            4 => XimErrorCode::BadFocusWindow,
+            5 => XimErrorCode::BadArea,
+            6 => XimErrorCode::BadSpotLocation,
+            7 => XimErrorCode::BadColormap,
+            8 => XimErrorCode::BadAtom,
+            9 => XimErrorCode::BadPixel,
+            10 => XimErrorCode::BadPixmap,
+            11 => XimErrorCode::BadName,
+            12 => XimErrorCode::BadCursor,
+            13 => XimErrorCode::BadProtocol,
+            14 => XimErrorCode::BadForeground,
+            15 => XimErrorCode::BadBackground,
+            16 => XimErrorCode::LocaleNotSupported,
+            _ => XimErrorCode::BadSomething,
+        }
+    }
+}
+
+/// Error reported by the IME server in response to a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XimError {
+    /// Error code as defined by the XIM protocol.
+    pub code: XimErrorCode,
+    /// Window of the input context the error refers to, if any.
+    pub window: Option<Window>,
+    /// Description of the error provided by the IME server.
+    pub detail: String,
+}
+
+impl fmt::Display for XimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "XIM error {:?}", self.code)?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for XimError {}
+
+/// Errors returned by [`ImeClient`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImeError {
+    /// There is no IME server running yet, see [`ImeClient::new`].
+    NoServer,
+    /// The connection to the IME server could not be opened.
+    OpenFailed,
+    /// The IME server did not create an input context for the window.
+    IcCreationFailed(Window),
+    /// The window has no input context that has been created by the IME server.
+    NoInputContext(Window),
+    /// A request could not be sent to the IME server.
+    RequestFailed,
+    /// The IME server replied with an error.
+    Server(XimError),
+    /// A string could not be encoded to be sent to the IME server or is too long to fit into a
+    /// frame of the XIM protocol.
+    Encoding,
+}
+
+impl fmt::Display for ImeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImeError::NoServer => write!(f, "no IME server found"),
+            ImeError::OpenFailed => write!(f, "failed to open the connection to the IME server"),
+            ImeError::IcCreationFailed(win) => write!(
+                f,
+                "failed to create the input context of window {}",
+                win.resource_id()
+            ),
+            ImeError::NoInputContext(win) => {
+                write!(f, "window {} has no input context", win.resource_id())
+            }
+            ImeError::RequestFailed => write!(f, "failed to send a request to the IME server"),
+            ImeError::Server(err) => write!(f, "IME server error: {}", err),
+            ImeError::Encoding => write!(f, "string cannot be encoded for the IME server"),
+        }
+    }
+}
+
+impl std::error::Error for ImeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImeError::Server(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn intern_atom(conn: &xcb::Connection, name: &[u8], only_if_exists: bool) -> Atom {
+    let cookie = conn.send_request(&InternAtom {
+        only_if_exists,
+        name,
+    });
+    conn.wait_for_reply(cookie)
+        .map_or(Atom::none(), |reply| reply.atom())
+}
+
+/// Check whether an IME server has registered itself on the root window of `screen_id`.
+fn xim_server_available(conn: &xcb::Connection, screen_id: i32) -> bool {
+    let root = match conn.get_setup().roots().nth(screen_id as usize) {
+        Some(screen) => screen.root(),
+        None => return false,
+    };
+    let servers = intern_atom(conn, b"XIM_SERVERS", true);
+    if servers.is_none() {
+        return false;
+    }
+    let cookie = conn.send_request(&GetProperty {
+        delete: false,
+        window: root,
+        property: servers,
+        r#type: ATOM_ATOM,
+        long_offset: 0,
+        long_length: 1,
+    });
+    conn.wait_for_reply(cookie)
+        .is_ok_and(|reply| !reply.value::<Atom>().is_empty())
+}
+
+/// Keysyms of all keycodes as needed to recognize trigger keys.
+struct KeyboardMapping {
+    min_keycode: u8,
+    keysyms_per_keycode: u8,
+    keysyms: Vec<Keysym>,
+}
+
+impl KeyboardMapping {
+    fn query(conn: &xcb::Connection) -> Option<Self> {
+        let setup = conn.get_setup();
+        let min_keycode = setup.min_keycode();
+        let cookie = conn.send_request(&GetKeyboardMapping {
+            first_keycode: min_keycode,
+            count: setup.max_keycode() - min_keycode + 1,
+        });
+        let reply = conn.wait_for_reply(cookie).ok()?;
+        Some(Self {
+            min_keycode,
+            keysyms_per_keycode: reply.keysyms_per_keycode(),
+            keysyms: reply.keysyms().to_vec(),
+        })
+    }
+
+    /// Keysym of `keycode`, taking only the shift modifier of `state` into account.
+    fn keysym(&self, keycode: u8, state: u16) -> Keysym {
+        let per_keycode = self.keysyms_per_keycode as usize;
+        let start = keycode.wrapping_sub(self.min_keycode) as usize * per_keycode;
+        let syms = match self.keysyms.get(start..start + per_keycode) {
+            Some(syms) => syms,
+            None => return 0,
+        };
+        let shift = state & xcb::x::KeyButMask::SHIFT.bits() as u16 != 0;
+        match syms {
+            [_, upper, ..] if shift && *upper != 0 => *upper,
+            [lower, ..] => *lower,
+            [] => 0,
+        }
+    }
+}
+
+/// Pick the first style of `preferred` that is part of `supported`.
+///
+/// Without such a style, the supported style sharing the most bits with the first preferred
+/// style it shares any with is picked, before falling back to the first supported style.
+fn negotiate_input_style<'a>(
+    preferred: impl Iterator<Item = &'a InputStyle> + Clone,
+    supported: &[InputStyle],
+) -> InputStyle {
+    let ignorable = InputStyle::PREEDIT_NOTHING
+        | InputStyle::PREEDIT_NONE
+        | InputStyle::STATUS_NOTHING
+        | InputStyle::STATUS_NONE;
+    if let Some(style) = preferred.clone().find(|style| supported.contains(style)) {
+        return *style;
+    }
+    for style in preferred.clone() {
+        if let Some(s) = supported
+            .iter()
+            .find(|s| s.contains(*style) && ignorable.contains(**s - *style))
+        {
+            return *s;
+        }
+    }
+    for style in preferred.clone() {
+        let shared = |s: &InputStyle| (*s & *style).bits().count_ones();
+        let best = supported.iter().filter(|s| shared(s) > 0).fold(
+            None,
+            |best: Option<&InputStyle>, s| match best {
+                Some(b) if shared(b) >= shared(s) => Some(b),
+                _ => Some(s),
+            },
+        );
+        if let Some(s) = best {
+            return *s;
+        }
+    }
+    let fallback = supported.first().copied().unwrap_or(InputStyle::DEFAULT);
+    log(&format!(
+        "None of the preferred input styles {:?} is supported, falling back to {:?}.",
+        preferred.collect::<Vec<_>>(),
+        fallback
+    ));
+    fallback
+}
+
+/// Read the error code, the input context ID if valid and the error detail of an `XIM_ERROR`
+/// frame, `frame` starts with the packet header.
+///
+/// Return `None` if `frame` is no `XIM_ERROR` frame or is truncated.
+fn read_error_frame(frame: &[u8]) -> Option<(XimErrorCode, Option<xcb_xic_t>, String)> {
+    if frame.first().map(|&opcode| opcode as u32) != Some(XCB_XIM_ERROR) {
+        return None;
+    }
+    // the IME server uses the byte order of the client, which xcb-imdkit announces as the native
+    // one when connecting
+    let card16 = |offset: usize| {
+        let bytes = frame.get(offset..offset + 2)?;
+        Some(u16::from_ne_bytes([bytes[0], bytes[1]]))
+    };
+    // the header is followed by the input method ID, which is not needed
+    let xic = card16(6)?;
+    let flag = card16(8)?;
+    let code = card16(10)?;
+    let len = card16(12)? as usize;
+    // the length is followed by the type of the detail
+    let detail = frame.get(16..16 + len)?;
+    Some((
+        XimErrorCode::from_raw(code),
+        // bit 1 of the flag marks the input context ID as valid
+        Some(xic).filter(|_| flag & 2 != 0),
+        String::from_utf8_lossy(detail).into_owned(),
+    ))
+}
+
+/// How [`ImeClient::handle_event`] used an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EventUse {
+    /// The event is a message of the XIM protocol.
+    Protocol,
+    /// The key event has been passed to the IME.
+    Consumed,
+    /// The event is not meant for the IME client.
+    Ignored,
+}
+
+/// Input Method Editor (IME) client.
+///
+/// [`ImeClient`] represents one instance of an Input Method Editor client. It provides callbacks for
 /// event handling as well as control over the position of the IME window. There should be only one
-/// IME client per application and it is advised to create at most one instance.
-pub struct ImeClient {
+/// IME client per application and it is advised to create at most one instance. Every window gets
+/// its own [`InputContext`].
+///
+/// Events can be handled by setting callbacks, by an [`ImeHandler`] passed to [`with_handler`]
+/// or by polling the queued [`ImeEvent`]s, see [`set_event_queue`].
+///
+/// [`with_handler`]: ImeClient::with_handler
+/// [`set_event_queue`]: ImeClient::set_event_queue
+pub struct ImeClient<H: ImeHandler = ()> {
     conn: Option<Arc<xcb::Connection>>,
+    raw_conn: *mut xcb::ffi::xcb_connection_t,
     im: *mut xcb_xim_t,
-    ic: Option<xcb_xic_t>,
+    is_im_open: bool,
+    has_server: bool,
+    reconnect_pending: bool,
+    is_reconnecting: bool,
+    supports_ext_move: bool,
+    is_querying_styles: bool,
+    supported_styles: Option<Vec<InputStyle>>,
+    ics: HashMap<Window, InputContext>,
+    pending_ics: VecDeque<Window>,
+    pending_im_queries: VecDeque<Box<ImValuesCB>>,
+    ic_attr_names: HashMap<u16, Vec<u8>>,
+    pending_event_masks: HashMap<xcb_xic_t, (EventMask, EventMask)>,
+    focus: Option<Window>,
+    track_focus: bool,
     callbacks: Callbacks,
-    input_style: InputStyle,
-    pos_cur: ImePos,
-    pos_req: ImePos,
-    is_processing_pos_update: bool,
-    pos_update_queued: bool,
+    input_styles: Vec<InputStyle>,
+    xim_protocol: Atom,
+    xim_moredata: Atom,
+    transport: Atom,
+    server_atom: Option<Atom>,
+    server_name: Option<String>,
+    frame_buf: Vec<u8>,
+    last_error: Option<XimError>,
+    dynamic_flow: bool,
+    keyboard_mapping: Option<KeyboardMapping>,
+    default_forward_mask: EventMask,
+    default_sync_mask: EventMask,
+    queue_events: bool,
+    events: VecDeque<ImeEvent>,
+    handler: H,
 }
 
+// the client is pinned as xcb-imdkit keeps a pointer to it, the handler is never pinned
+impl<H: ImeHandler> Unpin for ImeClient<H> {}
+
 impl ImeClient {
     /// Set the global logger for xcb-imdkit.
     ///
@@ -377,209 +1953,1287 @@ impl ImeClient {
         LOGGER.lock().unwrap().replace(Box::new(f));
     }
 
-    /// Create a new [`ImeClient`].
+    /// Create a new [`ImeClient`].
+    ///
+    /// The first two arguments correspond to the result of [`xcb::Connection::connect`] with the
+    /// connection wrapped into an [`Arc`] to ensure that the `Ime` does not outlive its
+    /// connection.
+    /// For documentation on `input_style` refer to [`InputStyle`], it is used for all input
+    /// contexts that are not created explicitly by [`create_ic`] if the IME server supports it.
+    /// `im_name` can be used to specify a custom IME server to connect to using the syntax
+    /// `@im=custom_server`.
+    ///
+    /// If no IME server is running yet, for example because the application has been started
+    /// before it, the connection is opened once a server has registered itself. Until then every
+    /// input context reports [`ImeError::NoServer`] through [`InputContext::error`] and key
+    /// events are not consumed by [`process_event`], so that the application can fall back to
+    /// plain keyboard input.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`Arc`]: std::sync::Arc
+    /// [`create_ic`]: ImeClient::create_ic
+    pub fn new(
+        conn: Arc<xcb::Connection>,
+        screen_id: i32,
+        input_style: InputStyle,
+        im_name: Option<&str>,
+    ) -> Result<Pin<Box<Self>>, ImeError> {
+        Self::with_handler(conn, screen_id, input_style, im_name, ())
+    }
+
+    /// Create a new [`ImeClient`].
+    ///
+    /// This is the same as [`new`], except that the [`xcb::Connection`] is not wrapped
+    /// into an [`Arc`].
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible to ensure that the [`ImeClient`] does not outlive the connection.
+    ///
+    /// [`Arc`]: std::sync::Arc
+    /// [`new`]: ImeClient::new
+    pub unsafe fn unsafe_new(
+        conn: &xcb::Connection,
+        screen_id: i32,
+        input_style: InputStyle,
+        im_name: Option<&str>,
+    ) -> Result<Pin<Box<Self>>, ImeError> {
+        Self::unsafe_with_handler(conn, screen_id, input_style, im_name, ())
+    }
+}
+
+impl<H: ImeHandler> ImeClient<H> {
+    /// Create a new [`ImeClient`] passing its events to `handler`.
+    ///
+    /// This is the same as [`new`], except that the methods of `handler` are called for every
+    /// event before the callback set for it, see [`ImeHandler`]. The handler can be accessed
+    /// with [`handler`] and [`handler_mut`].
+    ///
+    /// [`new`]: ImeClient::new
+    /// [`handler`]: ImeClient::handler
+    /// [`handler_mut`]: ImeClient::handler_mut
+    pub fn with_handler(
+        conn: Arc<xcb::Connection>,
+        screen_id: i32,
+        input_style: InputStyle,
+        im_name: Option<&str>,
+        handler: H,
+    ) -> Result<Pin<Box<Self>>, ImeError> {
+        let mut res =
+            unsafe { Self::unsafe_with_handler(&conn, screen_id, input_style, im_name, handler)? };
+        res.conn = Some(conn);
+        Ok(res)
+    }
+
+    /// Create a new [`ImeClient`] passing its events to `handler`.
+    ///
+    /// This is the same as [`with_handler`], except that the [`xcb::Connection`] is not wrapped
+    /// into an [`Arc`].
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible to ensure that the [`ImeClient`] does not outlive the connection.
+    ///
+    /// [`Arc`]: std::sync::Arc
+    /// [`with_handler`]: ImeClient::with_handler
+    pub unsafe fn unsafe_with_handler(
+        conn: &xcb::Connection,
+        screen_id: i32,
+        input_style: InputStyle,
+        im_name: Option<&str>,
+        handler: H,
+    ) -> Result<Pin<Box<Self>>, ImeError> {
+        let has_server = xim_server_available(conn, screen_id);
+        if !has_server {
+            log("No IME server is running, waiting for one to register itself.");
+        }
+        let im_name = match im_name {
+            Some(name) => Some(CString::new(name).map_err(|_| ImeError::Encoding)?),
+            None => None,
+        };
+        xcb_compound_text_init();
+        let im = xcb_xim_create(
+            conn.get_raw_conn() as _,
+            screen_id,
+            im_name
+                .as_ref()
+                .map_or(std::ptr::null(), |name| name.as_ptr()),
+        );
+        if im.is_null() {
+            return Err(ImeError::OpenFailed);
+        }
+        let mut res = Box::pin(Self {
+            conn: None,
+            raw_conn: conn.get_raw_conn(),
+            im,
+            is_im_open: false,
+            has_server,
+            reconnect_pending: false,
+            is_reconnecting: false,
+            supports_ext_move: false,
+            is_querying_styles: false,
+            supported_styles: None,
+            ics: HashMap::new(),
+            pending_ics: VecDeque::new(),
+            pending_im_queries: VecDeque::new(),
+            ic_attr_names: HashMap::new(),
+            pending_event_masks: HashMap::new(),
+            focus: None,
+            track_focus: true,
+            callbacks: Callbacks::default(),
+            input_styles: vec![input_style],
+            xim_protocol: intern_atom(conn, b"_XIM_PROTOCOL", false),
+            xim_moredata: intern_atom(conn, b"_XIM_MOREDATA", false),
+            transport: intern_atom(conn, b"TRANSPORT", false),
+            server_atom: None,
+            server_name: None,
+            frame_buf: Vec::new(),
+            last_error: None,
+            dynamic_flow: false,
+            keyboard_mapping: None,
+            default_forward_mask: DEFAULT_FORWARD_MASK,
+            default_sync_mask: EventMask::empty(),
+            queue_events: false,
+            events: VecDeque::new(),
+            handler,
+        });
+        let callbacks = xcb_xim_im_callback {
+            set_event_mask: Some(set_event_mask_callback::<H>),
+            disconnected: Some(disconnected_callback::<H>),
+            commit_string: Some(commit_string_callback::<H>),
+            forward_event: Some(forward_event_callback::<H>),
+            preedit_start: Some(preedit_start_callback::<H>),
+            preedit_draw: Some(preedit_draw_callback::<H>),
+            preedit_caret: Some(preedit_caret_callback::<H>),
+            preedit_done: Some(preedit_done_callback::<H>),
+            status_start: Some(status_start_callback::<H>),
+            status_draw_text: Some(status_draw_text_callback::<H>),
+            status_draw_bitmap: Some(status_draw_bitmap_callback::<H>),
+            status_done: Some(status_done_callback::<H>),
+            geometry: Some(geometry_callback::<H>),
+            // `XIM_SYNC` is a request of the IME server, not the answer to a synchronous event
+            sync: None,
+        };
+        let data: *mut Self = res.as_mut().get_mut();
+        xcb_xim_set_im_callback(im, &callbacks, data as _);
+        xcb_xim_set_log_handler(im, Some(xcb_log_wrapper));
+        xcb_xim_set_use_compound_text(im, true);
+        xcb_xim_set_use_utf8_string(im, true);
+        res.try_open_im()?;
+        Ok(res)
+    }
+
+    /// The handler passed to [`with_handler`].
+    ///
+    /// [`with_handler`]: ImeClient::with_handler
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// The handler passed to [`with_handler`], for example to update the state of the
+    /// application it holds.
+    ///
+    /// [`with_handler`]: ImeClient::with_handler
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    fn user_data(&mut self) -> *mut c_void {
+        self as *mut Self as _
+    }
+
+    fn try_open_im(&mut self) -> Result<(), ImeError> {
+        if self.is_im_open {
+            self.create_pending_ics();
+            return Ok(());
+        }
+        let data = self.user_data();
+        if !unsafe { xcb_xim_open(self.im, Some(open_callback::<H>), true, data) } {
+            return Err(ImeError::OpenFailed);
+        }
+        Ok(())
+    }
+
+    fn create_pending_ics(&mut self) {
+        if !self.is_im_open || self.is_querying_styles {
+            return;
+        }
+        let im = self.im;
+        let data = self.user_data();
+        for ic in self.ics.values_mut() {
+            if ic.xic.is_some() || ic.is_creating || ic.error.is_some() {
+                continue;
+            }
+            let preferred = ic.preferred_style.iter().chain(self.input_styles.iter());
+            ic.input_style = match &self.supported_styles {
+                Some(supported) => negotiate_input_style(preferred, supported),
+                None => *preferred.clone().next().unwrap_or(&InputStyle::DEFAULT),
+            };
+            let input_style = ic.input_style.bits();
+            let spot = xcb_point_t {
+                x: ic.pos_req.x,
+                y: ic.pos_req.y,
+            };
+            let w = ic.win.resource_id();
+            // an error left by an earlier request must not be mistaken for the reply to this one
+            self.last_error = None;
+            let created = unsafe {
+                let preedit = ic.preedit_attrs.create_nested_list(im, Some(&spot));
+                let status = ic.status_attrs.create_nested_list(im, None);
+                let mut args = vec![
+                    xim_arg(XCB_XIM_XNInputStyle, &input_style),
+                    xim_arg(XCB_XIM_XNClientWindow, &w),
+                    xim_arg(XCB_XIM_XNFocusWindow, &w),
+                    xim_arg(XCB_XIM_XNPreeditAttributes, &preedit),
+                ];
+                if !ic.status_attrs.is_empty() {
+                    args.push(xim_arg(XCB_XIM_XNStatusAttributes, &status));
+                }
+                let created = call_variadic!(xcb_xim_create_ic(im, Some(create_ic_callback::<H>), data; &args));
+                free(preedit.data as _);
+                free(status.data as _);
+                created
+            };
+            if created {
+                ic.is_creating = true;
+                ic.pos_cur = ic.pos_req;
+                self.pending_ics.push_back(ic.win);
+            } else {
+                ic.error = Some(ImeError::IcCreationFailed(ic.win));
+            }
+        }
+    }
+
+    fn ic_by_xic(&mut self, xic: xcb_xic_t) -> Option<&mut InputContext> {
+        self.ics.values_mut().find(|ic| ic.xic == Some(xic))
+    }
+
+    /// Clear the preedit text of `xic`, see [`PreeditState`].
+    fn clear_preedit(&mut self, xic: xcb_xic_t) {
+        match self.ic_by_xic(xic) {
+            Some(ic) if ic.preedit != PreeditState::default() => ic.preedit.clear(),
+            _ => return,
+        }
+        self.preedit_changed(xic);
+    }
+
+    /// Pass the preedit text of `xic` to the handler and the callback once it has changed.
+    fn preedit_changed(&mut self, xic: xcb_xic_t) {
+        let ic = match self.ics.values_mut().find(|ic| ic.xic == Some(xic)) {
+            Some(ic) => ic,
+            None => return,
+        };
+        self.handler.preedit_changed(ic.win, &ic.preedit);
+        let global = self.callbacks.preedit_state.as_mut();
+        if let Some(f) = ic.callbacks.preedit_state.as_mut().or(global) {
+            f(ic.win, &ic.preedit);
+        }
+    }
+
+    fn ic_window(&self, xic: xcb_xic_t) -> Option<Window> {
+        self.ics
+            .values()
+            .find(|ic| ic.xic == Some(xic))
+            .map(|ic| ic.win)
+    }
+
+    /// Queue `event` if events are queued, see [`ImeEvent`].
+    fn push_event(&mut self, event: ImeEvent) {
+        if self.queue_events {
+            self.events.push_back(event);
+        }
+    }
+
+    /// Queue the event created by `f` for the window of `xic` if events are queued.
+    fn queue_event(&mut self, xic: xcb_xic_t, f: impl FnOnce(Window) -> ImeEvent) {
+        if !self.queue_events {
+            return;
+        }
+        if let Some(win) = self.ic_window(xic) {
+            let event = f(win);
+            self.events.push_back(event);
+        }
+    }
+
+    /// Look up the callback to call for `xic`, callbacks of the input context take precedence
+    /// over the ones of the client.
+    fn callback<T: ?Sized>(
+        &mut self,
+        xic: xcb_xic_t,
+        pick: fn(&mut Callbacks) -> &mut Option<Box<T>>,
+    ) -> Option<(Window, &mut Box<T>)> {
+        let global = &mut self.callbacks;
+        let ic = self.ics.values_mut().find(|ic| ic.xic == Some(xic))?;
+        let win = ic.win;
+        match pick(&mut ic.callbacks) {
+            Some(f) => Some((win, f)),
+            None => pick(global).as_mut().map(|f| (win, f)),
+        }
+    }
+
+    /// Set the input styles to use in order of preference.
+    ///
+    /// Once the connection to the IME server is established, the input styles supported by the
+    /// server are queried and each input context is created with the first style of `styles`
+    /// the server supports. A style also matches a supported style that only adds
+    /// [`InputStyle::PREEDIT_NOTHING`], [`InputStyle::PREEDIT_NONE`],
+    /// [`InputStyle::STATUS_NOTHING`] or [`InputStyle::STATUS_NONE`] to it. If none of the
+    /// styles is supported, the supported style containing the most preedit and status flags of
+    /// the first style any supported style shares flags with is used. Without such a style, the
+    /// first style offered by the server is used.
+    ///
+    /// This replaces the input style passed to [`ImeClient::new`] and only affects input
+    /// contexts created afterwards.
+    pub fn set_preferred_input_styles(&mut self, styles: &[InputStyle]) {
+        self.input_styles = styles.to_vec();
+    }
+
+    /// Input styles supported by the IME server.
+    ///
+    /// Return `None` if the connection to the IME server has not been established yet or the
+    /// server did not report its input styles.
+    pub fn supported_input_styles(&self) -> Option<&[InputStyle]> {
+        self.supported_styles.as_deref()
+    }
+
+    /// Queue the events of the IME as [`ImeEvent`]s.
+    ///
+    /// While enabled, [`process_event`] and the other methods talking to the IME server queue an
+    /// [`ImeEvent`] for everything the IME reports, in addition to calling the callbacks. The
+    /// events are retrieved with [`poll_event`] or [`drain_events`]. This way the events can be
+    /// handled with access to the state of the application rather than from within callbacks.
+    /// Disabling the queue drops the events that have not been retrieved yet.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`poll_event`]: ImeClient::poll_event
+    /// [`drain_events`]: ImeClient::drain_events
+    pub fn set_event_queue(&mut self, enabled: bool) {
+        self.queue_events = enabled;
+        if !enabled {
+            self.events.clear();
+        }
+    }
+
+    /// Take the oldest event from the queue, see [`set_event_queue`].
+    ///
+    /// [`set_event_queue`]: ImeClient::set_event_queue
+    pub fn poll_event(&mut self) -> Option<ImeEvent> {
+        self.events.pop_front()
+    }
+
+    /// Take all events from the queue in the order they occurred, see [`set_event_queue`].
+    ///
+    /// [`set_event_queue`]: ImeClient::set_event_queue
+    pub fn drain_events(&mut self) -> Drain<'_, ImeEvent> {
+        self.events.drain(..)
+    }
+
+    /// Query what the IME server is capable of.
+    ///
+    /// `f` is called with the [`ImValues`] once the IME server has answered. The input styles are
+    /// queried anew while the other values are those negotiated when the connection was opened.
+    ///
+    /// Fail with [`ImeError::RequestFailed`] without calling `f` if the connection to the IME
+    /// server has not been established yet.
+    pub fn query_im_values<F>(&mut self, f: F) -> Result<(), ImeError>
+    where
+        F: FnOnce(ImValues) + 'static,
+    {
+        if !self.is_im_open {
+            return Err(ImeError::RequestFailed);
+        }
+        let sent = unsafe {
+            xcb_xim_get_im_values(
+                self.im,
+                Some(im_values_callback::<H>),
+                self.user_data(),
+                XCB_XIM_XNQueryInputStyle,
+                std::ptr::null_mut::<c_void>(),
+            )
+        };
+        if !sent {
+            return Err(ImeError::RequestFailed);
+        }
+        self.pending_im_queries.push_back(Box::new(f));
+        Ok(())
+    }
+
+    /// Create an [`InputContext`] for `win` using `input_style`.
+    ///
+    /// The input context is created on the IME server as soon as the connection to it has been
+    /// established. If the server does not support `input_style`, the styles set by
+    /// [`set_preferred_input_styles`] are tried next. If `win` already has an input context, the
+    /// existing one is returned unchanged.
+    ///
+    /// Fail if the connection to the IME server cannot be opened, the input context is not kept
+    /// in that case. If no IME server is running yet or if the IME server failed to create the
+    /// input context, it is returned nonetheless and the reason is reported by
+    /// [`InputContext::error`] until the input context has been created.
+    ///
+    /// [`set_preferred_input_styles`]: ImeClient::set_preferred_input_styles
+    pub fn create_ic(
+        &mut self,
+        win: Window,
+        input_style: InputStyle,
+    ) -> Result<&mut InputContext, ImeError> {
+        self.insert_ic(win, Some(input_style))
+    }
+
+    fn insert_ic(
+        &mut self,
+        win: Window,
+        preferred_style: Option<InputStyle>,
+    ) -> Result<&mut InputContext, ImeError> {
+        let input_style = preferred_style
+            .or_else(|| self.input_styles.first().copied())
+            .unwrap_or(InputStyle::DEFAULT);
+        if let Entry::Vacant(entry) = self.ics.entry(win) {
+            let ic = entry.insert(InputContext::new(win, preferred_style, input_style));
+            if !self.has_server {
+                ic.error = Some(ImeError::NoServer);
+            }
+            if let Err(err) = self.try_open_im() {
+                self.ics.remove(&win);
+                return Err(err);
+            }
+        }
+        Ok(self.ics.get_mut(&win).unwrap())
+    }
+
+    /// Destroy the [`InputContext`] of `win`.
+    ///
+    /// This should be called once a window is destroyed. Return `false` if `win` had no input
+    /// context.
+    pub fn destroy_ic(&mut self, win: Window) -> bool {
+        let ic = match self.ics.remove(&win) {
+            Some(ic) => ic,
+            None => return false,
+        };
+        if let Some(xic) = ic.xic {
+            unsafe { xcb_xim_destroy_ic(self.im, xic, None, std::ptr::null_mut()) };
+        }
+        if self.focus == Some(win) {
+            self.focus = None;
+        }
+        true
+    }
+
+    /// Get the [`InputContext`] of `win`.
+    pub fn input_context(&self, win: Window) -> Option<&InputContext> {
+        self.ics.get(&win)
+    }
+
+    /// Get the [`InputContext`] of `win` mutably, for example to set callbacks specific to it.
+    pub fn input_context_mut(&mut self, win: Window) -> Option<&mut InputContext> {
+        self.ics.get_mut(&win)
+    }
+
+    /// Tell the IME that `win` received the keyboard focus.
+    ///
+    /// The input context of the previously focused window loses the focus. If `win` has no
+    /// [`InputContext`] yet, it receives the focus once it has been created. By default this is
+    /// done automatically for `FocusIn` events passed to [`process_event`], see
+    /// [`set_focus_tracking`]. Input contexts are no longer focused when they are created, so
+    /// applications that disable focus tracking have to call this method; otherwise the
+    /// window of the first key event is focused.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`set_focus_tracking`]: ImeClient::set_focus_tracking
+    pub fn focus_in(&mut self, win: Window) {
+        if self.focus == Some(win) {
+            return;
+        }
+        if let Some(focus) = self.focus {
+            self.focus_out(focus);
+        }
+        self.focus = Some(win);
+        if let Some(xic) = self.ics.get(&win).and_then(|ic| ic.xic) {
+            unsafe { xcb_xim_set_ic_focus(self.im, xic) };
+        }
+    }
+
+    /// Tell the IME that `win` lost the keyboard focus.
+    ///
+    /// This makes the IME hide its windows, such as the candidate window, for the input context
+    /// of `win`. By default this is done automatically for `FocusOut` events passed to
+    /// [`process_event`], see [`set_focus_tracking`].
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`set_focus_tracking`]: ImeClient::set_focus_tracking
+    pub fn focus_out(&mut self, win: Window) {
+        if self.focus != Some(win) {
+            return;
+        }
+        self.focus = None;
+        if let Some(xic) = self.ics.get(&win).and_then(|ic| ic.xic) {
+            unsafe { xcb_xim_unset_ic_focus(self.im, xic) };
+        }
+    }
+
+    /// Window that currently has the focus as set by [`focus_in`].
+    ///
+    /// [`focus_in`]: ImeClient::focus_in
+    pub fn focused_window(&self) -> Option<Window> {
+        self.focus
+    }
+
+    /// Enable or disable updating the focus from `FocusIn` and `FocusOut` events.
+    ///
+    /// If enabled, which is the default, [`process_event`] calls [`focus_in`] and [`focus_out`]
+    /// for the respective events. Disable it to control the focus manually.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`focus_in`]: ImeClient::focus_in
+    /// [`focus_out`]: ImeClient::focus_out
+    pub fn set_focus_tracking(&mut self, enabled: bool) {
+        self.track_focus = enabled;
+    }
+
+    /// Enable or disable the dynamic event flow.
+    ///
+    /// By default all key events are forwarded to the IME server (static event flow). In the
+    /// dynamic event flow key events are left to the application until one of the trigger keys
+    /// registered by the IME server turns the IME on. From then on key events are forwarded to
+    /// the IME server until it is turned off again by a trigger key. This saves a roundtrip to the
+    /// IME server for every key while the IME is off, but requires an IME server that registers
+    /// trigger keys, otherwise the IME can never be turned on.
+    ///
+    /// See [`is_ime_active`] and [`set_ime_active_cb`] to find out whether the IME is on.
+    ///
+    /// [`is_ime_active`]: ImeClient::is_ime_active
+    /// [`set_ime_active_cb`]: ImeClient::set_ime_active_cb
+    pub fn set_dynamic_flow(&mut self, enabled: bool) {
+        self.dynamic_flow = enabled;
+    }
+
+    /// Return `true` if key events of `win` are forwarded to the IME server.
+    ///
+    /// This is always the case in the static event flow, in the dynamic event flow only while the
+    /// IME has been turned on by a trigger key, see [`set_dynamic_flow`].
+    ///
+    /// [`set_dynamic_flow`]: ImeClient::set_dynamic_flow
+    pub fn is_ime_active(&self, win: Window) -> bool {
+        match self.ics.get(&win) {
+            Some(ic) => !self.dynamic_flow || ic.is_triggered,
+            None => !self.dynamic_flow,
+        }
+    }
+
+    fn keysym(&mut self, keycode: u8, state: u16) -> Keysym {
+        if self.keyboard_mapping.is_none() {
+            // the connection is owned by the application, it must not be closed here
+            let conn = unsafe {
+                xcb::Connection::from_raw_conn_and_extensions_no_drop(self.raw_conn, &[], &[])
+            };
+            self.keyboard_mapping = KeyboardMapping::query(&conn);
+        }
+        self.keyboard_mapping
+            .as_ref()
+            .map_or(0, |mapping| mapping.keysym(keycode, state))
+    }
+
+    /// Handle the trigger keys of the dynamic event flow.
+    ///
+    /// Return `None` if `event` is to be forwarded to the IME server, otherwise whether it has
+    /// been consumed.
+    fn check_trigger_key(
+        &mut self,
+        win: Window,
+        xic: xcb_xic_t,
+        event: &xcb_key_press_event_t,
+    ) -> Option<bool> {
+        let is_triggered = self.ics[&win].is_triggered;
+        let forward = if is_triggered { None } else { Some(false) };
+        if (event.response_type & !0x80) != XCB_KEY_PRESS {
+            return forward;
+        }
+        let keysym = self.keysym(event.detail, event.state);
+        let modifier = event.state as u32;
+        let mut idx = 0;
+        let is_trigger = unsafe {
+            if is_triggered {
+                xcb_xim_check_trigger_off_key(self.im, keysym, modifier, &mut idx)
+            } else {
+                xcb_xim_check_trigger_on_key(self.im, keysym, modifier, &mut idx)
+            }
+        };
+        if !is_trigger {
+            return forward;
+        }
+        if !unsafe { xcb_xim_trigger_notify(self.im, xic, idx, is_triggered) } {
+            return forward;
+        }
+        self.ics.get_mut(&win).unwrap().is_triggered = !is_triggered;
+        self.handler.ime_active(win, !is_triggered);
+        self.push_event(ImeEvent::ImeActive(win, !is_triggered));
+        if let Some((win, f)) = self.callback(xic, |cbs| &mut cbs.ime_active) {
+            f(win, !is_triggered);
+        }
+        Some(true)
+    }
+
+    fn update_focus(&mut self, event: &xcb::Event) {
+        match event {
+            xcb::Event::X(xcb::x::Event::FocusIn(e)) if e.detail() != NotifyDetail::Pointer => {
+                self.focus_in(e.event());
+            }
+            xcb::Event::X(xcb::x::Event::FocusOut(e)) if e.detail() != NotifyDetail::Pointer => {
+                self.focus_out(e.event());
+            }
+            _ => {}
+        }
+    }
+
+    /// Let the IME client process XCB's events.
+    ///
+    /// Return `true` if the IME client is handling the event and `false` if the event is ignored
+    /// by the IME client and has to be handled separately.
+    ///
+    /// This method should be called on **any** event from the event queue and not just
+    /// keypress/keyrelease events as it handles other events as well.
+    ///
+    /// Typically you will want to let the IME client handle all keypress/keyrelease events in your
+    /// main loop. The IME client will then forward all key events that were not used for input
+    /// composition to the callback set by [`set_forward_event_cb`]. Often those events include all
+    /// keyrelease events as well as the events for `ESC`, `Enter` or key combinations such as
+    /// `CTRL+C`. If the IME server goes away, for example because it is restarted, the connection
+    /// is reestablished once a server is available again and all input contexts are recreated
+    /// with their last position and attributes, see [`set_disconnected_cb`]. `FocusIn` and
+    /// `FocusOut` events update the focus of the input contexts unless disabled by
+    /// [`set_focus_tracking`].
+    /// Key events are sent to the input context of the window they occurred in, or to the input
+    /// context that has the focus if that window has none. If there is no input context at all,
+    /// one is created for the window of the event. If no window has the focus and focus tracking
+    /// is enabled, the target of the first key event receives it, as earlier versions focused
+    /// every input context on creation; applications that disable focus tracking have to call
+    /// [`focus_in`] themselves. Only the key
+    /// events the IME server asked for are forwarded, see [`wants_event`].
+    /// To obtain the text currently typed into the IME and the final string consult
+    /// [`set_preedit_draw_cb`] and [`set_commit_string_cb`].
+    ///
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    /// [`set_focus_tracking`]: ImeClient::set_focus_tracking
+    /// [`set_disconnected_cb`]: ImeClient::set_disconnected_cb
+    /// [`focus_in`]: ImeClient::focus_in
+    /// [`wants_event`]: ImeClient::wants_event
+    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
+        self.handle_event(event) == EventUse::Consumed
+    }
+
+    /// Process `event` as [`process_event`] does, telling XIM protocol messages apart from
+    /// events not meant for the IME client.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    pub(crate) fn handle_event(&mut self, event: &xcb::Event) -> EventUse {
+        if self.reconnect_pending {
+            self.reconnect_pending = false;
+            self.is_reconnecting = true;
+            if let Err(err) = self.try_open_im() {
+                log(&format!("Failed to reconnect to the IME server: {}", err));
+            }
+        }
+        let im = self.im;
+        for ic in self.ics.values_mut() {
+            ic.release_expired_events(im);
+        }
+        self.intercept_frame(event);
+        self.note_server_selection(event);
+        let raw = event.as_raw();
+        if unsafe { xcb_xim_filter_event(self.im, raw as _) } {
+            return EventUse::Protocol;
+        }
+        if let xcb::Event::X(xcb::x::Event::ClientMessage(e)) = event {
+            // messages of a connection xcb-imdkit has already dropped
+            if e.r#type() == self.xim_protocol || e.r#type() == self.xim_moredata {
+                return EventUse::Protocol;
+            }
+        }
+        if self.track_focus {
+            self.update_focus(event);
+        }
+        if let xcb::Event::X(xcb::x::Event::MappingNotify(_)) = event {
+            self.keyboard_mapping = None;
+        }
+        let mask = unsafe { (*raw).response_type & !0x80 };
+        if (mask == XCB_KEY_PRESS) || (mask == XCB_KEY_RELEASE) {
+            let event_win = unsafe { Window::new((*(raw as *const xcb_key_press_event_t)).event) };
+            let win = match self.key_event_target(event_win) {
+                Some(win) => win,
+                None => {
+                    if self.insert_ic(event_win, None).is_err() {
+                        return EventUse::Ignored;
+                    }
+                    event_win
+                }
+            };
+            if self.track_focus && self.focus.is_none() {
+                self.focus_in(win);
+            }
+            match self.ics[&win].xic {
+                Some(ic) => {
+                    if self.dynamic_flow {
+                        let key_event = unsafe { &*(raw as *const xcb_key_press_event_t) };
+                        match self.check_trigger_key(win, ic, key_event) {
+                            Some(true) => return EventUse::Consumed,
+                            Some(false) => return EventUse::Ignored,
+                            None => {}
+                        }
+                    }
+                    let key_event = unsafe { *(raw as *const xcb_key_press_event_t) };
+                    let ic = self.ics.get_mut(&win).unwrap();
+                    if !ic.forward_mask.contains(event_type_mask(&key_event)) {
+                        return EventUse::Ignored;
+                    }
+                    ic.forward_event(self.im, key_event);
+                    return EventUse::Consumed;
+                }
+                _ => {
+                    let _ = self.try_open_im();
+                }
+            }
+        }
+        EventUse::Ignored
+    }
+
+    /// Window whose input context receives the key events of `event_win`.
+    fn key_event_target(&self, event_win: Window) -> Option<Window> {
+        if self.ics.contains_key(&event_win) {
+            return Some(event_win);
+        }
+        self.focus.filter(|win| self.ics.contains_key(win))
+    }
+
+    /// Return `false` if passing `event` to [`process_event`] has no effect.
+    ///
+    /// This allows to skip events the IME is not interested in, for example keyrelease events if
+    /// the IME server only asked for keypress events to be forwarded, see
+    /// [`InputContext::forward_event_mask`]. Events needed to communicate with the IME server
+    /// are always wanted.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    pub fn wants_event(&self, event: &xcb::Event) -> bool {
+        let (event_win, type_mask) = match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(e)) => (e.event(), EventMask::KEY_PRESS),
+            xcb::Event::X(xcb::x::Event::KeyRelease(e)) => (e.event(), EventMask::KEY_RELEASE),
+            xcb::Event::X(xcb::x::Event::FocusIn(_))
+            | xcb::Event::X(xcb::x::Event::FocusOut(_)) => return self.track_focus,
+            xcb::Event::X(xcb::x::Event::MappingNotify(_)) => return self.dynamic_flow,
+            xcb::Event::X(xcb::x::Event::ClientMessage(_))
+            | xcb::Event::X(xcb::x::Event::PropertyNotify(_))
+            | xcb::Event::X(xcb::x::Event::SelectionNotify(_))
+            | xcb::Event::X(xcb::x::Event::DestroyNotify(_)) => return true,
+            _ => return self.reconnect_pending,
+        };
+        let ic = match self.key_event_target(event_win) {
+            Some(win) => &self.ics[&win],
+            None => return self.default_forward_mask.contains(type_mask),
+        };
+        if self.dynamic_flow && !ic.is_triggered {
+            // key presses are checked for trigger keys
+            return type_mask == EventMask::KEY_PRESS;
+        }
+        ic.forward_mask.contains(type_mask)
+    }
+
+    /// Collect the `XIM_OPEN_REPLY`, `XIM_ERROR` and `XIM_SYNC_REPLY` frames of the IME server
+    /// before xcb-imdkit consumes them.
+    ///
+    /// Frames split into several client messages are put back together. Frames transferred
+    /// through window properties are only read while the connection to the IME server is being
+    /// opened, as reading them takes a round trip to the X server.
+    ///
+    /// This duplicates the frame handling of xcb-imdkit, which is only needed as xcb-imdkit does
+    /// not pass these frames on: `xcb_xim_im_callback` has no slot for errors, the attribute IDs
+    /// of `XIM_OPEN_REPLY` are not exposed and `XIM_SYNC_REPLY` is not reported.
+    fn intercept_frame(&mut self, event: &xcb::Event) {
+        let event = match event {
+            xcb::Event::X(xcb::x::Event::ClientMessage(event)) => event,
+            _ => return,
+        };
+        let is_moredata = event.r#type() == self.xim_moredata;
+        if !is_moredata && event.r#type() != self.xim_protocol {
+            return;
+        }
+        let data = match event.data() {
+            ClientMessageData::Data8(data) if event.format() == 8 => data.to_vec(),
+            ClientMessageData::Data32(data) if event.format() == 32 && !self.is_im_open => {
+                match self.read_frame_property(event.window(), Atom::new(data[1]), data[0]) {
+                    Some(data) => data,
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        if is_moredata {
+            self.frame_buf.extend_from_slice(&data);
+            return;
+        }
+        let mut frame = std::mem::take(&mut self.frame_buf);
+        frame.extend_from_slice(&data);
+        let opcode = frame.first().map(|&opcode| opcode as u32);
+        if opcode == Some(XCB_XIM_OPEN_REPLY) {
+            self.parse_open_reply(&frame);
+        } else if opcode == Some(XCB_XIM_SYNC_REPLY) {
+            self.handle_sync_reply(&frame);
+        } else if let Some(err) = self.parse_error_frame(&frame) {
+            log(&format!("Received error from the IME server: {}", err));
+            self.handler.error(&err);
+            if let Some(f) = self.callbacks.error.as_mut() {
+                f(err.clone());
+            }
+            self.push_event(ImeEvent::Error(err.clone()));
+            self.last_error = Some(err);
+        }
+    }
+
+    /// Remember the selection of the IME server xcb-imdkit is connecting to.
+    ///
+    /// xcb-imdkit does not tell which of the servers registered in `XIM_SERVERS` it connected to.
+    /// The transport of a server is queried by converting its `@server=` selection, which is the
+    /// last conversion before the connection is opened.
+    fn note_server_selection(&mut self, event: &xcb::Event) {
+        if let xcb::Event::X(xcb::x::Event::SelectionNotify(e)) = event {
+            if !self.is_im_open && e.target() == self.transport {
+                self.server_atom = Some(e.selection());
+            }
+        }
+    }
+
+    /// Name of the IME server owning the selection `atom`, without the `@server=` prefix.
+    fn server_name_of(&self, atom: Atom) -> Option<String> {
+        // the connection is owned by the application, it must not be closed here
+        let conn = unsafe {
+            xcb::Connection::from_raw_conn_and_extensions_no_drop(self.raw_conn, &[], &[])
+        };
+        let cookie = conn.send_request(&GetAtomName { atom });
+        let reply = conn.wait_for_reply(cookie).ok()?;
+        let name = reply.name().to_utf8();
+        name.strip_prefix("@server=").map(str::to_owned)
+    }
+
+    /// Read a frame of `length` bytes the IME server transferred through `property` of `win`.
+    ///
+    /// The property is left for xcb-imdkit to read and delete.
+    fn read_frame_property(&self, win: Window, property: Atom, length: u32) -> Option<Vec<u8>> {
+        // the connection is owned by the application, it must not be closed here
+        let conn = unsafe {
+            xcb::Connection::from_raw_conn_and_extensions_no_drop(self.raw_conn, &[], &[])
+        };
+        let cookie = conn.send_request(&GetProperty {
+            delete: false,
+            window: win,
+            property,
+            r#type: ATOM_ANY,
+            long_offset: 0,
+            long_length: length,
+        });
+        let reply = conn.wait_for_reply(cookie).ok()?;
+        if reply.format() != 8 {
+            return None;
+        }
+        let mut frame = reply.value::<u8>().to_vec();
+        frame.truncate(length as usize);
+        Some(frame)
+    }
+
+    /// Remember the IDs the IME server assigned to the attributes of input contexts, `frame` is
+    /// an `XIM_OPEN_REPLY` frame starting with the packet header.
+    ///
+    /// xcb-imdkit does not expose them, they are needed to match the values replied to
+    /// [`get_ic_values`] to the queried attributes.
+    ///
+    /// [`get_ic_values`]: ImeClient::get_ic_values
+    fn parse_open_reply(&mut self, frame: &[u8]) {
+        if frame.len() < 4 {
+            return;
+        }
+        unsafe {
+            let mut fr: xcb_im_open_reply_fr_t = std::mem::zeroed();
+            let mut data = frame[4..].as_ptr() as *mut u8;
+            let mut len = frame.len() - 4;
+            xcb_im_open_reply_fr_read(&mut fr, &mut data, &mut len, false);
+            if data.is_null() {
+                return;
+            }
+            let attrs = &fr.IC_attribute_supported;
+            if !attrs.items.is_null() {
+                self.ic_attr_names = std::slice::from_raw_parts(attrs.items, attrs.size as usize)
+                    .iter()
+                    .map(|attr| {
+                        let name = value_from_raw(attr.ic_attribute, attr.length_of_ic_attribute);
+                        (attr.attribute_ID, name.to_vec())
+                    })
+                    .collect();
+            }
+            xcb_im_open_reply_fr_free(&mut fr);
+        }
+    }
+
+    /// Forward the key events queued until the IME server answered a synchronous event, `frame`
+    /// is an `XIM_SYNC_REPLY` frame starting with the packet header.
+    ///
+    /// xcb-imdkit does not report the answer, so without this the queued events would only be
+    /// released after [`SYNC_TIMEOUT`].
+    fn handle_sync_reply(&mut self, frame: &[u8]) {
+        if frame.len() < 8 {
+            return;
+        }
+        let xic = u16::from_ne_bytes([frame[6], frame[7]]);
+        let im = self.im;
+        if let Some(ic) = self.ic_by_xic(xic) {
+            ic.release_pending_events(im);
+        }
+    }
+
+    /// Parse an `XIM_ERROR` frame, `frame` starts with the packet header.
+    fn parse_error_frame(&self, frame: &[u8]) -> Option<XimError> {
+        let (code, xic, detail) = read_error_frame(frame)?;
+        let window = xic.and_then(|xic| {
+            self.ics
+                .values()
+                .find(|ic| ic.xic == Some(xic))
+                .map(|ic| ic.win)
+        });
+        Some(XimError {
+            code,
+            window,
+            detail,
+        })
+    }
+
+    /// Set the position at which to place the IME window.
+    ///
+    /// Set the position of the IME window relative to the window specified by `win`. Coordinates
+    /// increase from the top left corner of the window. If `win` has no [`InputContext`] yet, one
+    /// is created using the preferred input styles.
+    ///
+    /// Return `true` if an update for the IME window position has been sent to the IME, `false` if
+    /// the update has been queued. If there is still an update request queued and this method is
+    /// called, the previously queued request is discarded in favor of the new one.
+    ///
+    /// If the IME server supports the `XIM_EXT_MOVE` extension, updates are sent right away
+    /// without waiting for the IME to answer the previous one.
+    pub fn update_pos(&mut self, win: Window, x: i16, y: i16) -> Result<bool, ImeError> {
+        self.insert_ic(win, None)?.pos_req = ImePos { x, y };
+        let im = self.im;
+        let data = self.user_data();
+        let ic = self.ics.get_mut(&win).unwrap();
+        let xic = match ic.xic {
+            Some(xic) => xic,
+            None => {
+                self.try_open_im()?;
+                return Ok(false);
+            }
+        };
+        if self.supports_ext_move {
+            if !unsafe { xcb_xim_ext_move(im, xic, x, y) } {
+                return Err(ImeError::RequestFailed);
+            }
+            // the IME does not answer XIM_EXT_MOVE, a queued update would only repeat it
+            ic.pos_update_queued = false;
+            ic.pos_cur = ic.pos_req;
+            return Ok(true);
+        }
+        if ic.is_processing_pos_update {
+            ic.pos_update_queued = true;
+            return Ok(false);
+        }
+        if !ic.send_pos_update::<H>(im, data) {
+            return Err(ImeError::RequestFailed);
+        }
+        Ok(true)
+    }
+
+    /// Set the attributes of the preedit area of `win`.
     ///
-    /// The first two arguments correspond to the result of [`xcb::Connection::connect`] with the
-    /// connection wrapped into an [`Arc`] to ensure that the `Ime` does not outlive its
-    /// connection.
-    /// For documentation on `input_style` refer to [`InputStyle`].
-    /// `im_name` can be used to specify a custom IME server to connect to using the syntax
-    /// `@im=custom_server`.
+    /// The attributes are sent along with the position set by [`update_pos`] when the input
+    /// context is created and sent again whenever this method is called. If `win` has no
+    /// [`InputContext`] yet, one is created using the preferred input styles.
     ///
-    /// [`Arc`]: std::sync::Arc
-    pub fn new(
-        conn: Arc<xcb::Connection>,
-        screen_id: i32,
-        input_style: InputStyle,
-        im_name: Option<&str>,
-    ) -> Pin<Box<Self>> {
-        let mut res = unsafe { Self::unsafe_new(&conn, screen_id, input_style, im_name) };
-        res.conn = Some(conn);
-        res
+    /// Return `true` if the attributes have been sent to the IME, `false` if they are sent once
+    /// the input context has been created. Fail with [`ImeError::Encoding`] if the font set
+    /// contains a nul character.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn set_preedit_attributes(
+        &mut self,
+        win: Window,
+        attrs: PreeditAttributes,
+    ) -> Result<bool, ImeError> {
+        attrs.check_encoding()?;
+        self.insert_ic(win, None)?.preedit_attrs = attrs;
+        self.send_attributes(win, XCB_XIM_XNPreeditAttributes, |ic| &ic.preedit_attrs)
     }
 
-    /// Create a new [`ImeClient`].
-    ///
-    /// This is the same as [`new`], except that the [`xcb::Connection`] is not wrapped
-    /// into an [`Arc`].
-    ///
-    /// # Safety
+    /// Set the attributes of the status area of `win`.
     ///
-    /// The caller is responsible to ensure that the [`ImeClient`] does not outlive the connection.
+    /// This works the same as [`set_preedit_attributes`] but for the status area.
     ///
-    /// [`Arc`]: std::sync::Arc
-    /// [`new`]: ImeClient::new
-    pub unsafe fn unsafe_new(
-        conn: &xcb::Connection,
-        screen_id: i32,
-        input_style: InputStyle,
-        im_name: Option<&str>,
-    ) -> Pin<Box<Self>> {
-        xcb_compound_text_init();
-        let im = xcb_xim_create(
-            conn.get_raw_conn() as _,
-            screen_id,
-            im_name.map_or(std::ptr::null(), |name| name.as_ptr() as _),
-        );
-        let mut res = Box::pin(Self {
-            conn: None,
-            im,
-            ic: None,
-            callbacks: Callbacks::default(),
-            input_style,
-            pos_cur: ImePos { win: 0, x: 0, y: 0 },
-            pos_req: ImePos { win: 0, x: 0, y: 0 },
-            is_processing_pos_update: false,
-            pos_update_queued: false,
-        });
-        let callbacks = xcb_xim_im_callback {
-            disconnected: Some(disconnected_callback),
-            commit_string: Some(commit_string_callback),
-            forward_event: Some(forward_event_callback),
-            preedit_start: Some(preedit_start_callback),
-            preedit_draw: Some(preedit_draw_callback),
-            preedit_done: Some(preedit_done_callback),
-            ..Default::default()
-        };
-        let data: *mut Self = res.as_mut().get_mut();
-        xcb_xim_set_im_callback(im, &callbacks, data as _);
-        xcb_xim_set_log_handler(im, Some(xcb_log_wrapper));
-        xcb_xim_set_use_compound_text(im, true);
-        xcb_xim_set_use_utf8_string(im, true);
-        res
+    /// [`set_preedit_attributes`]: ImeClient::set_preedit_attributes
+    pub fn set_status_attributes(
+        &mut self,
+        win: Window,
+        attrs: PreeditAttributes,
+    ) -> Result<bool, ImeError> {
+        attrs.check_encoding()?;
+        self.insert_ic(win, None)?.status_attrs = attrs;
+        self.send_attributes(win, XCB_XIM_XNStatusAttributes, |ic| &ic.status_attrs)
     }
 
-    fn try_open_ic(&mut self) {
-        if self.ic.is_some() {
-            return;
-        }
-        let data: *mut ImeClient = self as _;
-        unsafe { xcb_xim_open(self.im, Some(open_callback), true, data as _) };
+    /// Grant the IME the area `area` of `win` to draw the preedit text in.
+    ///
+    /// This only changes [`PreeditAttributes::area`] of the attributes set by
+    /// [`set_preedit_attributes`], see there for the return value. The size the IME would like to
+    /// use can be queried with [`query_area_needed`].
+    ///
+    /// [`set_preedit_attributes`]: ImeClient::set_preedit_attributes
+    /// [`query_area_needed`]: ImeClient::query_area_needed
+    pub fn set_preedit_area(&mut self, win: Window, area: Rectangle) -> Result<bool, ImeError> {
+        let attrs = self.insert_ic(win, None)?.preedit_attrs.clone().area(area);
+        self.set_preedit_attributes(win, attrs)
     }
 
-    /// Let the IME client process XCB's events.
+    /// Grant the IME the area `area` of `win` to draw its status in.
     ///
-    /// Return `true` if the IME client is handling the event and `false` if the event is ignored
-    /// by the IME client and has to be handled separately.
+    /// This works the same as [`set_preedit_area`] but for the status area.
     ///
-    /// This method should be called on **any** event from the event queue and not just
-    /// keypress/keyrelease events as it handles other events as well.
+    /// [`set_preedit_area`]: ImeClient::set_preedit_area
+    pub fn set_status_area(&mut self, win: Window, area: Rectangle) -> Result<bool, ImeError> {
+        let attrs = self.insert_ic(win, None)?.status_attrs.clone().area(area);
+        self.set_status_attributes(win, attrs)
+    }
+
+    /// Ask the IME how large it would like its preedit and status areas of `win` to be.
     ///
-    /// Typically you will want to let the IME client handle all keypress/keyrelease events in your
-    /// main loop. The IME client will then forward all key events that were not used for input
-    /// composition to the callback set by [`set_forward_event_cb`]. Often those events include all
-    /// keyrelease events as well as the events for `ESC`, `Enter` or key combinations such as
-    /// `CTRL+C`.
-    /// To obtain the text currently typed into the IME and the final string consult
-    /// [`set_preedit_draw_cb`] and [`set_commit_string_cb`].
+    /// `f` is called with the window and the [`AreaNeeded`] once the IME has answered. Only the
+    /// areas the input style of the [`InputContext`] lets the IME draw by itself are queried,
+    /// that is [`InputStyle::PREEDIT_AREA`] and [`InputStyle::STATUS_AREA`]. If the input style
+    /// contains neither, `f` is called right away. The suggested sizes can be set as
+    /// [`PreeditAttributes::area_needed`] beforehand to give the IME a hint.
     ///
-    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
-    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
-    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
-    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
-        let raw = event.as_raw();
-        if !unsafe { xcb_xim_filter_event(self.im, raw as _) } {
-            let mask = unsafe { (*raw).response_type & !0x80 };
-            if (mask == XCB_KEY_PRESS) || (mask == XCB_KEY_RELEASE) {
-                match self.ic {
-                    Some(ic) => {
-                        unsafe {
-                            xcb_xim_forward_event(self.im, ic, raw as _);
-                        }
-                        return true;
+    /// Fail with [`ImeError::NoInputContext`] without calling `f` if `win` has no input context
+    /// that has been created by the IME server.
+    pub fn query_area_needed<F>(&mut self, win: Window, f: F) -> Result<(), ImeError>
+    where
+        F: FnOnce(Window, AreaNeeded) + 'static,
+    {
+        let ic = self.ics.get(&win).ok_or(ImeError::NoInputContext(win))?;
+        let mut kinds = vec![];
+        if ic.input_style.contains(InputStyle::PREEDIT_AREA) {
+            kinds.push(IcAttributeKind::Preedit(AreaAttributeKind::AreaNeeded));
+        }
+        if ic.input_style.contains(InputStyle::STATUS_AREA) {
+            kinds.push(IcAttributeKind::Status(AreaAttributeKind::AreaNeeded));
+        }
+        self.get_ic_values(win, &kinds, |win, values| {
+            let mut area_needed = AreaNeeded::default();
+            for value in values {
+                match value {
+                    IcAttribute::Preedit(AreaAttribute::AreaNeeded(area)) => {
+                        area_needed.preedit = Some(area)
                     }
-                    _ => {
-                        self.try_open_ic();
+                    IcAttribute::Status(AreaAttribute::AreaNeeded(area)) => {
+                        area_needed.status = Some(area)
                     }
+                    _ => {}
                 }
             }
-        }
-        false
+            f(win, area_needed);
+        })
     }
 
-    /// Set the position at which to place the IME window.
+    /// Set arbitrary attributes of the input context of `win`.
     ///
-    /// Set the position of the IME window relative to the window specified by `win`. Coordinates
-    /// increase from the top left corner of the window.
+    /// Attributes of the preedit and the status area are sent as one nested list each. If an
+    /// attribute is given several times, the last value is sent. Unlike [`update_pos`] and
+    /// [`set_preedit_attributes`], the attributes are not remembered and thus not sent again when
+    /// the input context is recreated after a reconnect.
     ///
-    /// Return `true` if an update for the IME window position has been sent to the IME, `false` if
-    /// the update has been queued. If there is still an update request queued and this method is
-    /// called, the previously queued request is discarded in favor of the new one.
-    pub fn update_pos(&mut self, win: Window, x: i16, y: i16) -> bool {
-        self.pos_req = ImePos {
-            win: win.resource_id(),
-            x,
-            y,
-        };
-        match self.ic {
-            Some(ic) => {
-                if self.is_processing_pos_update {
-                    self.pos_update_queued = true;
-                    return false;
+    /// Fail with [`ImeError::NoInputContext`] if `win` has no input context that has been created
+    /// by the IME server, and with [`ImeError::Encoding`] if a font set contains a nul character.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`set_preedit_attributes`]: ImeClient::set_preedit_attributes
+    pub fn set_ic_values(&mut self, win: Window, attrs: &[IcAttribute]) -> Result<(), ImeError> {
+        let xic = self.created_xic(win)?;
+        let mut values = vec![];
+        let mut preedit = vec![];
+        let mut status = vec![];
+        for attr in attrs {
+            let value = attr.xim_value()?;
+            let (list, name) = match attr.kind() {
+                IcAttributeKind::Preedit(kind) => (&mut preedit, kind.name()),
+                IcAttributeKind::Status(kind) => (&mut status, kind.name()),
+                kind => (&mut values, kind.name()),
+            };
+            // each attribute is sent once, which keeps the number of arguments within bounds
+            list.retain(|(other, _)| *other != name);
+            list.push((name, value));
+        }
+        let sent = unsafe {
+            let to_args = |values: &[(&'static [u8], XimValue)]| -> Vec<XimArg> {
+                values
+                    .iter()
+                    .map(|(name, value)| (name.as_ptr() as _, value.as_ptr()))
+                    .collect()
+            };
+            let mut nested = vec![];
+            for (name, values) in [
+                (XCB_XIM_XNPreeditAttributes.as_ref(), &preedit),
+                (XCB_XIM_XNStatusAttributes.as_ref(), &status),
+            ] {
+                if !values.is_empty() {
+                    nested.push((name, create_nested_list(self.im, &to_args(values))));
                 }
-                self.send_pos_update(ic);
-                true
             }
-            _ => {
-                self.try_open_ic();
-                false
+            let mut args = to_args(&values);
+            args.extend(
+                nested
+                    .iter()
+                    .map(|(name, list)| (name.as_ptr() as _, list as *const _ as _)),
+            );
+            let sent = call_variadic!(xcb_xim_set_ic_values(
+                self.im,
+                xic,
+                None,
+                std::ptr::null_mut();
+                &args
+            ));
+            for (_, list) in nested {
+                free(list.data as _);
             }
+            sent
+        };
+        if !sent {
+            return Err(ImeError::RequestFailed);
         }
+        Ok(())
     }
 
-    fn send_pos_update(&mut self, ic: xcb_xic_t) {
-        self.is_processing_pos_update = true;
-        let spot = xcb_point_t {
-            x: self.pos_req.x,
-            y: self.pos_req.y,
+    /// Query attributes of the input context of `win` from the IME.
+    ///
+    /// `f` is called with the window and the values of the attributes `kinds` once the IME has
+    /// answered, or right away if `kinds` is empty. The values of the preedit and the status area
+    /// follow the other ones, attributes the IME did not answer are missing.
+    ///
+    /// Fail with [`ImeError::NoInputContext`] without calling `f` if `win` has no input context
+    /// that has been created by the IME server.
+    pub fn get_ic_values<F>(
+        &mut self,
+        win: Window,
+        kinds: &[IcAttributeKind],
+        f: F,
+    ) -> Result<(), ImeError>
+    where
+        F: FnOnce(Window, Vec<IcAttribute>) + 'static,
+    {
+        let xic = self.created_xic(win)?;
+        if kinds.is_empty() {
+            f(win, vec![]);
+            return Ok(());
+        }
+        let data = self.user_data();
+        let names = query_names(kinds);
+        let sent = unsafe {
+            call_variadic_names!(xcb_xim_get_ic_values(
+                self.im,
+                xic,
+                Some(ic_values_callback::<H>),
+                data;
+                &names
+            ))
+        };
+        if !sent {
+            return Err(ImeError::RequestFailed);
+        }
+        let ic = self.ics.get_mut(&win).unwrap();
+        ic.pending_value_queries
+            .push_back((kinds.to_vec(), Box::new(f)));
+        Ok(())
+    }
+
+    /// Input context of `win` that has been created by the IME server.
+    fn created_xic(&self, win: Window) -> Result<xcb_xic_t, ImeError> {
+        let ic = self.ics.get(&win).ok_or(ImeError::NoInputContext(win))?;
+        if let Some(err) = &ic.error {
+            return Err(err.clone());
+        }
+        ic.xic.ok_or(ImeError::NoInputContext(win))
+    }
+
+    fn send_attributes(
+        &mut self,
+        win: Window,
+        name: &'static [u8],
+        pick: fn(&InputContext) -> &PreeditAttributes,
+    ) -> Result<bool, ImeError> {
+        let ic = &self.ics[&win];
+        let xic = match ic.xic {
+            Some(xic) => xic,
+            None => return Ok(false),
         };
-        let nested = unsafe {
-            xcb_xim_create_nested_list(
+        let attrs = pick(ic);
+        if attrs.is_empty() {
+            return Ok(true);
+        }
+        let sent = unsafe {
+            let nested = attrs.create_nested_list(self.im, None);
+            let sent = xcb_xim_set_ic_values(
                 self.im,
-                XCB_XIM_XNSpotLocation,
-                &spot,
+                xic,
+                None,
+                std::ptr::null_mut(),
+                name.as_ptr(),
+                &nested,
                 std::ptr::null_mut::<c_void>(),
-            )
+            );
+            free(nested.data as _);
+            sent
         };
-        if self.pos_req.win != self.pos_cur.win {
-            let w = &mut self.pos_req.win as *mut _;
-            unsafe {
-                xcb_xim_set_ic_values(
-                    self.im,
-                    ic,
-                    Some(update_pos_callback),
-                    self as *mut _ as _,
-                    XCB_XIM_XNClientWindow,
-                    w,
-                    XCB_XIM_XNFocusWindow,
-                    w,
-                    XCB_XIM_XNPreeditAttributes,
-                    &nested,
-                    std::ptr::null_mut::<c_void>(),
-                );
-            }
-        } else {
-            unsafe {
-                xcb_xim_set_ic_values(
-                    self.im,
-                    ic,
-                    Some(update_pos_callback),
-                    self as *mut _ as _,
-                    XCB_XIM_XNPreeditAttributes,
-                    &nested,
-                    std::ptr::null_mut::<c_void>(),
-                );
-            }
+        if !sent {
+            return Err(ImeError::RequestFailed);
+        }
+        Ok(true)
+    }
+
+    /// Abort the current input composition of `win`.
+    ///
+    /// The IME discards its preedit text and `f` is called with the window and the text that was
+    /// pending when the input context was reset. Depending on the IME this text is empty. This is
+    /// useful if the user clicks elsewhere in the document or the application replaces the text
+    /// that is being edited.
+    ///
+    /// Fail with [`ImeError::NoInputContext`] without calling `f` if `win` has no input context
+    /// that has been created by the IME server.
+    pub fn reset<F>(&mut self, win: Window, f: F) -> Result<(), ImeError>
+    where
+        F: FnOnce(Window, String) + 'static,
+    {
+        let im = self.im;
+        let data = self.user_data();
+        let ic = self
+            .ics
+            .get_mut(&win)
+            .ok_or(ImeError::NoInputContext(win))?;
+        if let Some(err) = &ic.error {
+            return Err(err.clone());
         }
-        unsafe { free(nested.data as _) };
-        self.pos_cur = self.pos_req;
+        let xic = ic.xic.ok_or(ImeError::NoInputContext(win))?;
+        if !unsafe { xcb_xim_reset_ic(im, xic, Some(reset_ic_callback::<H>), data) } {
+            return Err(ImeError::RequestFailed);
+        }
+        ic.pending_resets.push_back(Box::new(f));
+        Ok(())
     }
 
     /// Set callback to be called once input composition is done.
     ///
-    /// The window (set by [`update_pos`]) as well as the completed input are passed as arguments.
+    /// The window of the [`InputContext`] as well as the completed input are passed as arguments.
+    /// This is a convenience for the string part of the commits passed to [`set_commit_cb`].
     ///
-    /// [`update_pos`]: ImeClient::update_pos
+    /// [`set_commit_cb`]: ImeClient::set_commit_cb
     pub fn set_commit_string_cb<F>(&mut self, f: F)
     where
         F: for<'a> FnMut(Window, &'a str) + 'static,
@@ -587,15 +3241,27 @@ impl ImeClient {
         self.callbacks.commit_string = Some(Box::new(f));
     }
 
+    /// Set callback to be called for everything the IME commits.
+    ///
+    /// The window of the [`InputContext`] and the [`Commit`] are passed as arguments. Unlike the
+    /// callback set by [`set_commit_string_cb`], which is called before this one, it also
+    /// receives commits that only contain keysyms.
+    ///
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    pub fn set_commit_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, Commit) + 'static,
+    {
+        self.callbacks.commit = Some(Box::new(f));
+    }
+
     // Set callback for keypress/keyrelease events unhandled by the IME.
     //
-    // The first argument passed is the window (set by [`update_pos`]), the second the key event.
+    // The first argument passed is the window of the [`InputContext`], the second the key event.
     /// Often those events include all keyrelease events as well as the events for `ESC`, `Enter`
     /// or key combinations such as `CTRL+C`. Please note that [`xcb::KeyPressEvent`] ==
     /// [`xcb::KeyReleaseEvent`] (see [`xcb::ffi::xcb_key_release_event_t`]) and keyrelease events
     /// are also supplied.
-    ///
-    /// [`update_pos`]: ImeClient::update_pos
     pub fn set_forward_event_cb<F>(&mut self, f: F)
     where
         F: for<'a> FnMut(Window, &'a xcb::Event) + 'static,
@@ -605,10 +3271,8 @@ impl ImeClient {
 
     /// Callback called once the IME has been opened.
     ///
-    /// The current window (set by [`update_pos`]) is supplied as argument.
+    /// The window of the [`InputContext`] is supplied as argument.
     /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
-    ///
-    /// [`update_pos`]: ImeClient::update_pos
     pub fn set_preedit_start_cb<F>(&mut self, f: F)
     where
         F: FnMut(Window) + 'static,
@@ -618,11 +3282,9 @@ impl ImeClient {
 
     /// Callback called whenever the text whitin the IME has changed.
     ///
-    /// The current window (set by [`update_pos`]) is supplied as argument as well as
+    /// The window of the [`InputContext`] is supplied as argument as well as
     /// [`PreeditInfo`], which contains, among other things, the current text of the IME.
     /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
-    ///
-    /// [`update_pos`]: ImeClient::update_pos
     pub fn set_preedit_draw_cb<F>(&mut self, f: F)
     where
         F: for<'a> FnMut(Window, PreeditInfo<'a>) + 'static,
@@ -630,28 +3292,357 @@ impl ImeClient {
         self.callbacks.preedit_draw = Some(Box::new(f));
     }
 
-    /// Callback called once the IME has been closed.
+    /// Callback called whenever the text whitin the IME has changed, with an owned copy of the
+    /// change.
     ///
-    /// The current window (set by [`update_pos`]) is supplied as argument.
+    /// The window of the [`InputContext`] is supplied as argument as well as [`PreeditUpdate`],
+    /// the result of [`PreeditInfo::to_owned`]. Other than [`PreeditInfo`] it can be sent to
+    /// other threads, for example to shape the text on a render thread. If a callback is set
+    /// with [`set_preedit_draw_cb`] as well, this one is called first.
     /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
     ///
-    /// [`update_pos`]: ImeClient::update_pos
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    pub fn set_preedit_update_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, PreeditUpdate) + 'static,
+    {
+        self.callbacks.preedit_update = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the preedit text has changed, with the whole text.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument as well as its
+    /// [`PreeditState`], to which the changes sent by the IME have already been applied. It is
+    /// called after the other preedit callbacks whenever the text, its feedback or the caret
+    /// have changed, including when the preedit text is cleared. The current preedit text is
+    /// also available from [`InputContext::preedit`].
+    /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
+    pub fn set_preedit_state_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, &'a PreeditState) + 'static,
+    {
+        self.callbacks.preedit_state = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the IME moves the caret within the preedit text.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument as well as [`PreeditCaret`],
+    /// which describes how the caret should be moved and drawn. The callback has to return the
+    /// new position of the caret in characters, which is reported back to the IME. Without a
+    /// callback, the caret of the [`PreeditState`] moved as requested is reported, see
+    /// [`PreeditState::move_caret`].
+    /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
+    pub fn set_preedit_caret_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, PreeditCaret) -> u32 + 'static,
+    {
+        self.callbacks.preedit_caret = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME has been closed.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument.
+    /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
     pub fn set_preedit_done_cb<F>(&mut self, f: F)
     where
         F: FnMut(Window) + 'static,
     {
         self.callbacks.preedit_done = Some(Box::new(f));
     }
+
+    /// Callback called once the status area of the IME should be shown.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument.
+    /// Calls callback only if [`InputStyle::STATUS_CALLBACKS`] is set.
+    pub fn set_status_start_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.status_start = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the status of the IME has changed.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument as well as [`StatusInfo`],
+    /// which contains either the status text or a pixmap to display.
+    /// Calls callback only if [`InputStyle::STATUS_CALLBACKS`] is set.
+    pub fn set_status_draw_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, StatusInfo) + 'static,
+    {
+        self.callbacks.status_draw = Some(Box::new(f));
+    }
+
+    /// Callback called once the status area of the IME should be hidden.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument.
+    /// Calls callback only if [`InputStyle::STATUS_CALLBACKS`] is set.
+    pub fn set_status_done_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.status_done = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME wants the geometry of its areas to be negotiated again.
+    ///
+    /// The window of the [`InputContext`] is supplied as argument. The application should ask for
+    /// the size the IME needs with [`query_area_needed`] and grant an area with
+    /// [`set_preedit_area`] or [`set_status_area`].
+    /// Calls callback only if [`InputStyle::PREEDIT_AREA`] or [`InputStyle::STATUS_AREA`] is set.
+    ///
+    /// [`query_area_needed`]: ImeClient::query_area_needed
+    /// [`set_preedit_area`]: ImeClient::set_preedit_area
+    /// [`set_status_area`]: ImeClient::set_status_area
+    pub fn set_geometry_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.callbacks.geometry = Some(Box::new(f));
+    }
+
+    /// Callback called once the IME is turned on or off by a trigger key.
+    ///
+    /// The window of the [`InputContext`] and whether the IME is now on are supplied as arguments.
+    /// Calls callback only in the dynamic event flow, see [`set_dynamic_flow`].
+    ///
+    /// [`set_dynamic_flow`]: ImeClient::set_dynamic_flow
+    pub fn set_ime_active_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, bool) + 'static,
+    {
+        self.callbacks.ime_active = Some(Box::new(f));
+    }
+
+    /// Callback called once the connection to the IME server has been lost.
+    ///
+    /// All input contexts are invalidated, the client tries to reconnect to the IME server on its
+    /// own and recreates them once a server is available again.
+    pub fn set_disconnected_cb<F>(&mut self, f: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.callbacks.disconnected = Some(Box::new(f));
+    }
+
+    /// Callback called once the connection to the IME server has been reestablished after it has
+    /// been lost.
+    ///
+    /// At this point all input contexts are being recreated with the position set by
+    /// [`update_pos`] and the attributes set by [`set_preedit_attributes`] and
+    /// [`set_status_attributes`].
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`set_preedit_attributes`]: ImeClient::set_preedit_attributes
+    /// [`set_status_attributes`]: ImeClient::set_status_attributes
+    pub fn set_reconnected_cb<F>(&mut self, f: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.callbacks.reconnected = Some(Box::new(f));
+    }
+
+    /// Callback called whenever the IME server replies with an error.
+    ///
+    /// [`XimError::window`] is set if the error refers to an input context, which allows to find
+    /// out which request, for example which call of [`set_preedit_attributes`], has been
+    /// rejected. Errors are only reported if they are passed to [`process_event`] as a client
+    /// message, which is the case unless the error description is very long.
+    ///
+    /// [`set_preedit_attributes`]: ImeClient::set_preedit_attributes
+    /// [`process_event`]: ImeClient::process_event
+    pub fn set_error_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(XimError) + 'static,
+    {
+        self.callbacks.error = Some(Box::new(f));
+    }
 }
 
-impl Drop for ImeClient {
+impl<H: ImeHandler> Drop for ImeClient<H> {
     fn drop(&mut self) {
         unsafe {
-            if let Some(ic) = self.ic {
-                xcb_xim_destroy_ic(self.im, ic, None, std::ptr::null_mut());
+            for ic in self.ics.values() {
+                if let Some(xic) = ic.xic {
+                    xcb_xim_destroy_ic(self.im, xic, None, std::ptr::null_mut());
+                }
             }
             xcb_xim_close(self.im);
             xcb_xim_destroy(self.im);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ON_THE_SPOT: InputStyle =
+        InputStyle::PREEDIT_CALLBACKS.union(InputStyle::STATUS_CALLBACKS);
+    const OVER_THE_SPOT: InputStyle =
+        InputStyle::PREEDIT_POSITION.union(InputStyle::STATUS_NOTHING);
+
+    /// `XIM_ERROR` frame with the fields in native byte order, the detail is padded to four
+    /// bytes.
+    fn error_frame(xic: u16, flag: u16, code: u16, detail: &str) -> Vec<u8> {
+        let mut frame = vec![XCB_XIM_ERROR as u8, 0, 0, 0];
+        for field in &[1, xic, flag, code, detail.len() as u16, 0] {
+            frame.extend_from_slice(&field.to_ne_bytes());
+        }
+        frame.extend_from_slice(detail.as_bytes());
+        frame.resize((frame.len() + 3) & !3, 0);
+        let length = (frame.len() as u16 - 4) / 4;
+        frame[2..4].copy_from_slice(&length.to_ne_bytes());
+        frame
+    }
+
+    fn styles_value(count: u16, styles: &[u32]) -> Vec<u8> {
+        let mut value = count.to_ne_bytes().to_vec();
+        value.extend_from_slice(&[0, 0]);
+        for style in styles {
+            value.extend_from_slice(&style.to_ne_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn negotiate_exact_match() {
+        let preferred = [ON_THE_SPOT, OVER_THE_SPOT];
+        let supported = [OVER_THE_SPOT, ON_THE_SPOT];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            ON_THE_SPOT
+        );
+    }
+
+    #[test]
+    fn negotiate_ignorable_bits() {
+        let preferred = [InputStyle::PREEDIT_POSITION];
+        let supported = [ON_THE_SPOT, OVER_THE_SPOT];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            OVER_THE_SPOT
+        );
+    }
+
+    #[test]
+    fn negotiate_shared_bits() {
+        // on-the-spot adds status callbacks, which are not ignorable
+        let preferred = [InputStyle::PREEDIT_CALLBACKS];
+        let supported = [OVER_THE_SPOT, ON_THE_SPOT];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            ON_THE_SPOT
+        );
+
+        // the style sharing the most bits wins, ties go to the first supported style
+        let preferred = [ON_THE_SPOT | InputStyle::PREEDIT_POSITION];
+        let supported = [
+            InputStyle::PREEDIT_CALLBACKS | InputStyle::STATUS_NOTHING,
+            OVER_THE_SPOT,
+            ON_THE_SPOT | InputStyle::PREEDIT_AREA,
+        ];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            supported[2]
+        );
+        let preferred = [InputStyle::PREEDIT_CALLBACKS | InputStyle::PREEDIT_POSITION];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            supported[0]
+        );
+
+        // earlier preferred styles take precedence over better matches of later ones
+        let preferred = [InputStyle::STATUS_NOTHING, ON_THE_SPOT];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            supported[0]
+        );
+    }
+
+    #[test]
+    fn negotiate_fallback() {
+        let preferred = [InputStyle::PREEDIT_AREA | InputStyle::STATUS_AREA];
+        let supported = [OVER_THE_SPOT, ON_THE_SPOT];
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &supported),
+            OVER_THE_SPOT
+        );
+        assert_eq!(
+            negotiate_input_style(preferred.iter(), &[]),
+            InputStyle::DEFAULT
+        );
+        assert_eq!(negotiate_input_style([].iter(), &supported), OVER_THE_SPOT);
+    }
+
+    #[test]
+    fn read_error() {
+        let frame = error_frame(3, 2, 2, "bad style");
+        assert_eq!(
+            read_error_frame(&frame),
+            Some((XimErrorCode::BadStyle, Some(3), "bad style".to_owned()))
+        );
+
+        // the input context ID is only valid if the flag says so
+        let frame = error_frame(3, 1, 999, "");
+        assert_eq!(
+            read_error_frame(&frame),
+            Some((XimErrorCode::BadSomething, None, String::new()))
+        );
+
+        // the fields are in native byte order
+        let mut frame = error_frame(0x0100, 0x0200, 0x0010, "");
+        assert_eq!(
+            read_error_frame(&frame),
+            Some((XimErrorCode::LocaleNotSupported, None, String::new()))
+        );
+        for field in frame[4..16].chunks_exact_mut(2) {
+            field.swap(0, 1);
+        }
+        assert_eq!(
+            read_error_frame(&frame),
+            Some((XimErrorCode::BadSomething, Some(0x0001), String::new()))
+        );
+
+        // other frames are ignored
+        let mut frame = error_frame(3, 2, 2, "");
+        frame[0] = XCB_XIM_SYNC_REPLY as u8;
+        assert_eq!(read_error_frame(&frame), None);
+    }
+
+    #[test]
+    fn read_truncated_error() {
+        let frame = error_frame(3, 2, 13, "detail");
+        // the padding may be missing, but not the detail
+        for len in 0..16 + "detail".len() {
+            assert_eq!(read_error_frame(&frame[..len]), None);
+        }
+        assert_eq!(
+            read_error_frame(&frame[..16 + "detail".len()]),
+            Some((XimErrorCode::BadProtocol, Some(3), "detail".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parse_input_styles() {
+        let value = styles_value(2, &[ON_THE_SPOT.bits(), OVER_THE_SPOT.bits()]);
+        assert_eq!(
+            input_styles_from_raw(&value),
+            vec![ON_THE_SPOT, OVER_THE_SPOT]
+        );
+
+        // the count limits the styles read, trailing bytes are ignored
+        let mut value = styles_value(1, &[ON_THE_SPOT.bits(), OVER_THE_SPOT.bits()]);
+        value.extend_from_slice(&[1, 2]);
+        assert_eq!(input_styles_from_raw(&value), vec![ON_THE_SPOT]);
+
+        // a truncated value yields the complete styles only
+        let mut value = styles_value(3, &[ON_THE_SPOT.bits()]);
+        value.extend_from_slice(&[1, 2]);
+        assert_eq!(input_styles_from_raw(&value), vec![ON_THE_SPOT]);
+
+        assert_eq!(input_styles_from_raw(&[]), vec![]);
+        assert_eq!(input_styles_from_raw(&[1, 0]), vec![]);
+        assert_eq!(input_styles_from_raw(&styles_value(0, &[])), vec![]);
+    }
+}
@@ -0,0 +1,259 @@
+use std::iter;
+
+use crate::{CaretDirection, InputFeedback, PreeditCaret, PreeditStatus, PreeditUpdate};
+
+/// Current preedit text of an input context, see [`InputContext::preedit`].
+///
+/// The IME server only sends the changes of the preedit text, [`PreeditState`] applies them to
+/// keep track of the whole text, its feedback and the caret. The [`ImeClient`] keeps one for every
+/// input context, it is cleared once input composition starts or ends, when the input context is
+/// reset and when the connection to the IME server is lost.
+///
+/// [`InputContext::preedit`]: crate::InputContext::preedit
+/// [`ImeClient`]: crate::ImeClient
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreeditState {
+    text: String,
+    feedback: Vec<InputFeedback>,
+    caret: u32,
+}
+
+impl PreeditState {
+    /// Create an empty preedit text.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whole preedit text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Feedback of each character of [`text`].
+    ///
+    /// [`text`]: PreeditState::text
+    pub fn feedback(&self) -> &[InputFeedback] {
+        &self.feedback
+    }
+
+    /// Cursor offset within the preedit text in characters.
+    pub fn caret(&self) -> u32 {
+        self.caret
+    }
+
+    /// Return `true` if there is no preedit text.
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Apply a change sent by the IME server.
+    ///
+    /// The characters from [`PreeditUpdate::chg_first`] counting [`PreeditUpdate::chg_length`]
+    /// are replaced by [`PreeditUpdate::text`]. If the update contains neither string nor
+    /// feedback, they are deleted. If it only contains feedback, only the feedback of these
+    /// characters is changed. Characters without feedback get [`InputFeedback::DEFAULT`].
+    pub fn apply(&mut self, update: &PreeditUpdate) {
+        let len = self.feedback.len();
+        let first = (update.chg_first as usize).min(len);
+        let end = first.saturating_add(update.chg_length as usize).min(len);
+        if !update.status.contains(PreeditStatus::NO_STRING) {
+            self.replace(first, end, &update.text, &update.feedback);
+        } else if update.status.contains(PreeditStatus::NO_FEEDBACK) {
+            self.replace(first, end, "", &[]);
+        } else {
+            let changed = self.feedback[first..end].iter_mut();
+            for (feedback, new) in changed.zip(&update.feedback) {
+                *feedback = *new;
+            }
+        }
+        self.caret = update.caret;
+    }
+
+    /// Set the caret, for example as answered to a [`PreeditCaret`].
+    pub fn set_caret(&mut self, caret: u32) {
+        self.caret = caret;
+    }
+
+    /// Move the caret as requested by the IME server.
+    ///
+    /// The caret is moved by one character, to the start or the end of the text or to
+    /// [`PreeditCaret::position`], without leaving the text. The other directions depend on how
+    /// the text is laid out and leave the caret unchanged.
+    pub fn move_caret(&mut self, caret: PreeditCaret) {
+        let len = self.feedback.len() as u32;
+        self.caret = match caret.direction {
+            CaretDirection::ForwardChar => self.caret.saturating_add(1).min(len),
+            CaretDirection::BackwardChar => self.caret.min(len).saturating_sub(1),
+            CaretDirection::LineStart => 0,
+            CaretDirection::LineEnd => len,
+            CaretDirection::AbsolutePosition => caret.position.min(len),
+            _ => self.caret,
+        };
+    }
+
+    /// Remove the preedit text.
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.feedback.clear();
+        self.caret = 0;
+    }
+
+    /// Replace the characters from `first` to `end` by `text`.
+    fn replace(&mut self, first: usize, end: usize, text: &str, feedback: &[InputFeedback]) {
+        let start = self.byte_offset(first);
+        let stop = self.byte_offset(end);
+        self.text.replace_range(start..stop, text);
+        let feedback = feedback
+            .iter()
+            .copied()
+            .chain(iter::repeat(InputFeedback::DEFAULT))
+            .take(text.chars().count());
+        self.feedback.splice(first..end, feedback);
+    }
+
+    /// Byte offset of the character at `idx` within the text.
+    fn byte_offset(&self, idx: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(idx)
+            .map_or(self.text.len(), |(offset, _)| offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CaretStyle;
+
+    fn update(
+        status: PreeditStatus,
+        caret: u32,
+        chg_first: u32,
+        chg_length: u32,
+        text: &str,
+        feedback: &[InputFeedback],
+    ) -> PreeditUpdate {
+        PreeditUpdate {
+            status,
+            caret,
+            chg_first,
+            chg_length,
+            text: text.to_owned(),
+            feedback: feedback.to_vec(),
+        }
+    }
+
+    fn state(text: &str, feedback: InputFeedback) -> PreeditState {
+        let mut state = PreeditState::new();
+        let feedback = vec![feedback; text.chars().count()];
+        state.apply(&update(PreeditStatus::empty(), 0, 0, 0, text, &feedback));
+        state
+    }
+
+    fn caret(direction: CaretDirection, position: u32) -> PreeditCaret {
+        PreeditCaret {
+            position,
+            direction,
+            style: CaretStyle::Primary,
+        }
+    }
+
+    const U: InputFeedback = InputFeedback::UNDERLINE;
+    const R: InputFeedback = InputFeedback::REVERSE;
+
+    #[test]
+    fn replace_range() {
+        let mut state = state("abcd", U);
+        state.apply(&update(PreeditStatus::empty(), 3, 1, 2, "xyz", &[R, R, R]));
+        assert_eq!(state.text(), "axyzd");
+        assert_eq!(state.feedback(), &[U, R, R, R, U]);
+        assert_eq!(state.caret(), 3);
+    }
+
+    #[test]
+    fn delete_range() {
+        let mut state = state("abcd", U);
+        let status = PreeditStatus::NO_STRING | PreeditStatus::NO_FEEDBACK;
+        state.apply(&update(status, 1, 1, 2, "", &[]));
+        assert_eq!(state.text(), "ad");
+        assert_eq!(state.feedback(), &[U, U]);
+        assert_eq!(state.caret(), 1);
+    }
+
+    #[test]
+    fn empty_string_deletes_range() {
+        let mut state = state("abcd", U);
+        state.apply(&update(PreeditStatus::empty(), 0, 0, 4, "", &[]));
+        assert!(state.is_empty());
+        assert!(state.feedback().is_empty());
+    }
+
+    #[test]
+    fn feedback_only() {
+        let mut state = state("abcd", U);
+        state.apply(&update(PreeditStatus::NO_STRING, 2, 1, 2, "", &[R, R]));
+        assert_eq!(state.text(), "abcd");
+        assert_eq!(state.feedback(), &[U, R, R, U]);
+        assert_eq!(state.caret(), 2);
+    }
+
+    #[test]
+    fn string_without_feedback() {
+        let mut state = state("ab", U);
+        state.apply(&update(PreeditStatus::NO_FEEDBACK, 3, 2, 0, "c", &[]));
+        assert_eq!(state.text(), "abc");
+        assert_eq!(state.feedback(), &[U, U, InputFeedback::DEFAULT]);
+    }
+
+    #[test]
+    fn change_past_end() {
+        let mut state = state("ab", U);
+        state.apply(&update(PreeditStatus::empty(), 3, 5, 2, "c", &[R]));
+        assert_eq!(state.text(), "abc");
+        assert_eq!(state.feedback(), &[U, U, R]);
+        let status = PreeditStatus::NO_STRING | PreeditStatus::NO_FEEDBACK;
+        state.apply(&update(status, 3, 7, 1, "", &[]));
+        assert_eq!(state.text(), "abc");
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let mut state = state("にほんご", U);
+        state.apply(&update(PreeditStatus::empty(), 2, 0, 3, "日本", &[R, R]));
+        assert_eq!(state.text(), "日本ご");
+        assert_eq!(state.feedback(), &[R, R, U]);
+        let status = PreeditStatus::NO_STRING | PreeditStatus::NO_FEEDBACK;
+        state.apply(&update(status, 1, 1, 1, "", &[]));
+        assert_eq!(state.text(), "日ご");
+    }
+
+    #[test]
+    fn clear() {
+        let mut state = state("abc", U);
+        state.clear();
+        assert_eq!(state, PreeditState::default());
+    }
+
+    #[test]
+    fn move_caret() {
+        let mut state = state("abc", U);
+        state.move_caret(caret(CaretDirection::ForwardChar, 42));
+        assert_eq!(state.caret(), 1);
+        state.move_caret(caret(CaretDirection::LineEnd, 42));
+        assert_eq!(state.caret(), 3);
+        state.move_caret(caret(CaretDirection::ForwardChar, 42));
+        assert_eq!(state.caret(), 3);
+        state.move_caret(caret(CaretDirection::BackwardChar, 42));
+        assert_eq!(state.caret(), 2);
+        state.move_caret(caret(CaretDirection::ForwardWord, 42));
+        assert_eq!(state.caret(), 2);
+        state.move_caret(caret(CaretDirection::LineStart, 42));
+        assert_eq!(state.caret(), 0);
+        state.move_caret(caret(CaretDirection::BackwardChar, 42));
+        assert_eq!(state.caret(), 0);
+        state.move_caret(caret(CaretDirection::AbsolutePosition, 2));
+        assert_eq!(state.caret(), 2);
+        state.move_caret(caret(CaretDirection::AbsolutePosition, 42));
+        assert_eq!(state.caret(), 3);
+    }
+}
@@ -0,0 +1,791 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use xcb::x::{EventMask, Rectangle, Window};
+use xcb::{Xid, XidNew};
+
+use crate::clib::*;
+use crate::{
+    key_event_from_raw, log, xcb_log_wrapper, CommitFlags, ImeError, InputFeedback, InputStyle,
+    PreeditAttributes, PreeditCaret, PreeditStatus, StatusInfo, XCB_KEY_PRESS, XCB_KEY_RELEASE,
+};
+
+/// Bits of the masks returned by `xcb_im_input_context_get_preedit_attr_mask` and
+/// `xcb_im_input_context_get_status_attr_mask` telling which attributes the client has set.
+///
+/// xcb-imdkit defines them in its sources (`deps/xcb-imdkit`) rather than its public headers, so
+/// they are missing from the bindings and have to be checked when updating xcb-imdkit. Bit 6 is
+/// not used by this crate.
+const ATTR_AREA: u32 = 1 << 0;
+const ATTR_AREA_NEEDED: u32 = 1 << 1;
+const ATTR_SPOT_LOCATION: u32 = 1 << 2;
+const ATTR_COLORMAP: u32 = 1 << 3;
+const ATTR_FOREGROUND: u32 = 1 << 4;
+const ATTR_BACKGROUND: u32 = 1 << 5;
+const ATTR_BACKGROUND_PIXMAP: u32 = 1 << 7;
+const ATTR_LINE_SPACE: u32 = 1 << 8;
+
+/// Types of `XIM_STATUS_DRAW`.
+const XIM_STATUS_TEXT: u32 = 0;
+const XIM_STATUS_BITMAP: u32 = 1;
+
+type ConnectCB = dyn FnMut();
+type InputContextCB = dyn FnMut(&mut ServerInputContext);
+type FocusCB = dyn FnMut(&mut ServerInputContext, bool);
+type KeyEventCB = dyn for<'a> FnMut(&mut ServerInputContext, &'a xcb::Event) -> bool;
+type ServerResetCB = dyn FnMut(&mut ServerInputContext) -> String;
+
+#[derive(Default)]
+struct ServerCallbacks {
+    connect: Option<Box<ConnectCB>>,
+    create_ic: Option<Box<InputContextCB>>,
+    destroy_ic: Option<Box<InputContextCB>>,
+    focus: Option<Box<FocusCB>>,
+    forward_event: Option<Box<KeyEventCB>>,
+    reset: Option<Box<ServerResetCB>>,
+}
+
+/// String converted to compound text, the encoding used to send strings to the clients.
+struct CompoundText {
+    data: *mut c_char,
+    len: usize,
+}
+
+impl CompoundText {
+    fn new(text: &str) -> Result<Self, ImeError> {
+        let mut len = 0usize;
+        let data = unsafe { xcb_utf8_to_compound_text(text.as_ptr() as _, text.len(), &mut len) };
+        if data.is_null() {
+            return Err(ImeError::Encoding);
+        }
+        let text = Self { data, len };
+        string_length(len)?;
+        Ok(text)
+    }
+
+    /// Give up ownership of the string, it has to be freed with `free`.
+    fn into_raw(self) -> (*mut c_char, usize) {
+        let res = (self.data, self.len);
+        std::mem::forget(self);
+        res
+    }
+}
+
+impl Drop for CompoundText {
+    fn drop(&mut self) {
+        unsafe { free(self.data as _) };
+    }
+}
+
+/// Check that a string of `len` bytes fits into a frame of the XIM protocol, which stores the
+/// length of a string in 16 bits.
+fn string_length(len: usize) -> Result<u16, ImeError> {
+    len.try_into().map_err(|_| ImeError::Encoding)
+}
+
+/// Status flags of `XIM_PREEDIT_DRAW` and `XIM_STATUS_DRAW` telling which parts are missing.
+fn draw_status(has_string: bool, has_feedback: bool) -> u32 {
+    let mut status = PreeditStatus::empty();
+    status.set(PreeditStatus::NO_STRING, !has_string);
+    status.set(PreeditStatus::NO_FEEDBACK, !has_feedback);
+    status.bits()
+}
+
+/// Data attached to the input contexts of xcb-imdkit to notice when they are freed.
+struct InputContextData {
+    server: *mut ImeServer,
+    id: usize,
+}
+
+extern "C" fn free_input_context_data(data: *mut c_void) {
+    let data = unsafe { Box::from_raw(data as *mut InputContextData) };
+    let server = unsafe { &mut *data.server };
+    server.ics.remove(&data.id);
+}
+
+extern "C" fn server_callback(
+    im: *mut xcb_im_t,
+    _client: *mut xcb_im_client_t,
+    ic: *mut xcb_im_input_context_t,
+    hdr: *const xcb_im_packet_header_fr_t,
+    _frame: *mut c_void,
+    arg: *mut c_void,
+    user_data: *mut c_void,
+) {
+    let server = unsafe { &mut *(user_data as *mut ImeServer) };
+    let opcode = unsafe { (*hdr).major_opcode } as u32;
+    if opcode == XCB_XIM_CONNECT {
+        if let Some(f) = server.callbacks.connect.as_mut() {
+            f();
+        }
+        return;
+    }
+    if ic.is_null() {
+        return;
+    }
+    if arg.is_null() && (opcode == XCB_XIM_FORWARD_EVENT || opcode == XCB_XIM_RESET_IC) {
+        return;
+    }
+    if opcode == XCB_XIM_CREATE_IC {
+        server.insert_ic(ic);
+    }
+    let callbacks = &mut server.callbacks;
+    let ctx = match server.ics.get_mut(&(ic as usize)) {
+        Some(ctx) => ctx,
+        None => return,
+    };
+    match opcode {
+        XCB_XIM_CREATE_IC => {
+            if let Some(f) = callbacks.create_ic.as_mut() {
+                f(ctx);
+            }
+        }
+        XCB_XIM_DESTROY_IC => {
+            if let Some(f) = callbacks.destroy_ic.as_mut() {
+                f(ctx);
+            }
+        }
+        XCB_XIM_SET_IC_FOCUS | XCB_XIM_UNSET_IC_FOCUS => {
+            if let Some(f) = callbacks.focus.as_mut() {
+                f(ctx, opcode == XCB_XIM_SET_IC_FOCUS);
+            }
+        }
+        XCB_XIM_FORWARD_EVENT => {
+            let raw = arg as *mut xcb_key_press_event_t;
+            let handled = match callbacks.forward_event.as_mut() {
+                Some(f) => {
+                    let event = unsafe { key_event_from_raw(raw) };
+                    let handled = f(ctx, &event);
+                    // the event is owned by xcb-imdkit
+                    std::mem::forget(event);
+                    handled
+                }
+                None => false,
+            };
+            if !handled {
+                unsafe { xcb_im_forward_event(im, ic, raw) };
+            }
+        }
+        XCB_XIM_RESET_IC => {
+            let text = match callbacks.reset.as_mut() {
+                Some(f) => f(ctx),
+                None => return,
+            };
+            if text.is_empty() {
+                return;
+            }
+            match CompoundText::new(&text) {
+                Ok(text) => {
+                    let (data, len) = text.into_raw();
+                    // xcb-imdkit frees the committed string after sending the reply
+                    let reply = unsafe { &mut *(arg as *mut xcb_im_reset_ic_reply_fr_t) };
+                    reply.committed_string = data as _;
+                    reply.byte_length_of_committed_string = len as u16;
+                }
+                Err(err) => log(&format!("Failed to send reset string: {}", err)),
+            }
+        }
+        _ => {}
+    }
+}
+
+fn rectangle_from_raw(rect: &xcb_rectangle_t) -> Rectangle {
+    Rectangle {
+        x: rect.x,
+        y: rect.y,
+        width: rect.width,
+        height: rect.height,
+    }
+}
+
+/// Decode the attributes of xcb-imdkit, only the attributes set in `mask` are valid.
+///
+/// The preedit and status attributes of xcb-imdkit share the same layout.
+fn attributes_from_raw(attr: &xcb_im_preedit_attr_t, mask: u32) -> PreeditAttributes {
+    let is_set = |bit| mask & bit != 0;
+    PreeditAttributes {
+        area: Some(rectangle_from_raw(&attr.area)).filter(|_| is_set(ATTR_AREA)),
+        area_needed: Some(rectangle_from_raw(&attr.area_needed))
+            .filter(|_| is_set(ATTR_AREA_NEEDED)),
+        colormap: Some(attr.colormap)
+            .filter(|_| is_set(ATTR_COLORMAP))
+            .map(XidNew::new),
+        foreground: Some(attr.foreground).filter(|_| is_set(ATTR_FOREGROUND)),
+        background: Some(attr.background).filter(|_| is_set(ATTR_BACKGROUND)),
+        background_pixmap: Some(attr.bg_pixmap)
+            .filter(|_| is_set(ATTR_BACKGROUND_PIXMAP))
+            .map(XidNew::new),
+        // xcb-imdkit does not keep the font set
+        font_set: None,
+        line_space: Some(attr.line_space).filter(|_| is_set(ATTR_LINE_SPACE)),
+    }
+}
+
+/// Decode the spot location of the preedit attributes of xcb-imdkit, if set in `mask`.
+fn spot_location_from_raw(attr: &xcb_im_preedit_attr_t, mask: u32) -> Option<(i16, i16)> {
+    Some((attr.spot_location.x, attr.spot_location.y)).filter(|_| mask & ATTR_SPOT_LOCATION != 0)
+}
+
+/// Input context of a client connected to the [`ImeServer`].
+///
+/// The methods sending preedit and status updates require the client to have created the input
+/// context with [`InputStyle::PREEDIT_CALLBACKS`] or [`InputStyle::STATUS_CALLBACKS`]
+/// respectively.
+///
+/// Arbitrary data, such as the composition state of the input method, can be attached to an
+/// input context with [`set_user_data`]. It is dropped once the input context is destroyed.
+///
+/// [`set_user_data`]: ServerInputContext::set_user_data
+pub struct ServerInputContext {
+    im: *mut xcb_im_t,
+    ic: *mut xcb_im_input_context_t,
+    user_data: Option<Box<dyn Any>>,
+}
+
+impl ServerInputContext {
+    /// Identifier of the input context, unique among the input contexts that currently exist.
+    pub fn id(&self) -> usize {
+        self.ic as usize
+    }
+
+    /// Input style the client created the input context with.
+    pub fn input_style(&self) -> InputStyle {
+        let style = unsafe { xcb_im_input_context_get_input_style(self.ic) };
+        InputStyle::from_bits_truncate(style)
+    }
+
+    /// Window of the client the input context belongs to.
+    pub fn client_window(&self) -> Window {
+        unsafe { Window::new(xcb_im_input_context_get_client_window(self.ic)) }
+    }
+
+    /// Window of the client that receives the input.
+    pub fn focus_window(&self) -> Window {
+        unsafe { Window::new(xcb_im_input_context_get_focus_window(self.ic)) }
+    }
+
+    /// Position of the caret in the focus window, if set by the client.
+    pub fn spot_location(&self) -> Option<(i16, i16)> {
+        unsafe {
+            let mask = xcb_im_input_context_get_preedit_attr_mask(self.ic);
+            spot_location_from_raw(&*xcb_im_input_context_get_preedit_attr(self.ic), mask)
+        }
+    }
+
+    /// Attributes of the preedit area set by the client.
+    ///
+    /// [`PreeditAttributes::font_set`] is always `None`.
+    pub fn preedit_attributes(&self) -> PreeditAttributes {
+        unsafe {
+            let mask = xcb_im_input_context_get_preedit_attr_mask(self.ic);
+            attributes_from_raw(&*xcb_im_input_context_get_preedit_attr(self.ic), mask)
+        }
+    }
+
+    /// Attributes of the status area set by the client.
+    ///
+    /// [`PreeditAttributes::font_set`] is always `None`.
+    pub fn status_attributes(&self) -> PreeditAttributes {
+        unsafe {
+            let mask = xcb_im_input_context_get_status_attr_mask(self.ic);
+            let attr = xcb_im_input_context_get_status_attr(self.ic);
+            attributes_from_raw(&*(attr as *const xcb_im_preedit_attr_t), mask)
+        }
+    }
+
+    /// Attach `data` to the input context, replacing any data attached before.
+    pub fn set_user_data<T: Any>(&mut self, data: T) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// Get the data attached to the input context if it is of type `T`.
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    /// Get the data attached to the input context mutably if it is of type `T`.
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
+    /// Remove the data attached to the input context and return it if it is of type `T`.
+    pub fn take_user_data<T: Any>(&mut self) -> Option<T> {
+        match self.user_data.take()?.downcast() {
+            Ok(data) => Some(*data),
+            Err(data) => {
+                self.user_data = Some(data);
+                None
+            }
+        }
+    }
+
+    /// Send `text` to the client as the result of the input composition.
+    pub fn commit_string(&mut self, text: &str) -> Result<(), ImeError> {
+        let text = CompoundText::new(text)?;
+        unsafe {
+            xcb_im_commit_string(
+                self.im,
+                self.ic,
+                CommitFlags::LOOKUP_CHARS.bits(),
+                text.data,
+                text.len as u32,
+                0,
+            )
+        };
+        Ok(())
+    }
+
+    /// Send a key event back to the client, for example because the input method does not use
+    /// it for input composition.
+    ///
+    /// Return `false` if `event` is neither a keypress nor a keyrelease event.
+    pub fn forward_event(&mut self, event: &xcb::Event) -> bool {
+        let raw = event.as_raw();
+        let response_type = unsafe { (*raw).response_type & !0x80 };
+        if response_type != XCB_KEY_PRESS && response_type != XCB_KEY_RELEASE {
+            return false;
+        }
+        unsafe { xcb_im_forward_event(self.im, self.ic, raw as _) };
+        true
+    }
+
+    /// Tell the client that input composition starts.
+    pub fn preedit_start(&mut self) {
+        unsafe { xcb_im_preedit_start_callback(self.im, self.ic) };
+    }
+
+    /// Replace `chg_length` characters of the preedit text starting at `chg_first` with `text`.
+    ///
+    /// `caret` is the new position of the caret and `feedback` contains the feedback of each
+    /// character of `text`.
+    pub fn preedit_draw(
+        &mut self,
+        text: &str,
+        caret: u32,
+        chg_first: u32,
+        chg_length: u32,
+        feedback: &[InputFeedback],
+    ) -> Result<(), ImeError> {
+        let text = if text.is_empty() {
+            None
+        } else {
+            Some(CompoundText::new(text)?)
+        };
+        let mut feedback: Vec<u32> = feedback.iter().map(|f| f.bits()).collect();
+        let status = draw_status(text.is_some(), !feedback.is_empty());
+        let mut frame = xcb_im_preedit_draw_fr_t {
+            input_method_ID: 0,
+            input_context_ID: 0,
+            caret,
+            chg_first,
+            chg_length,
+            status,
+            length_of_preedit_string: text.as_ref().map_or(0, |text| text.len as u16),
+            preedit_string: text
+                .as_ref()
+                .map_or(std::ptr::null_mut(), |text| text.data as _),
+            feedback_array: _xcb_im_preedit_draw_fr_t__bindgen_ty_1 {
+                size: feedback.len() as u32,
+                items: feedback.as_mut_ptr(),
+            },
+        };
+        unsafe { xcb_im_preedit_draw_callback(self.im, self.ic, &mut frame) };
+        Ok(())
+    }
+
+    /// Move the caret within the preedit text without redrawing it.
+    pub fn preedit_caret(&mut self, caret: PreeditCaret) {
+        let mut frame = xcb_im_preedit_caret_fr_t {
+            input_method_ID: 0,
+            input_context_ID: 0,
+            position: caret.position,
+            direction: caret.direction as u32,
+            style: caret.style as u32,
+        };
+        unsafe { xcb_im_preedit_caret_callback(self.im, self.ic, &mut frame) };
+    }
+
+    /// Tell the client that input composition is done.
+    pub fn preedit_done(&mut self) {
+        unsafe { xcb_im_preedit_done_callback(self.im, self.ic) };
+    }
+
+    /// Tell the client to show its status area.
+    pub fn status_start(&mut self) {
+        unsafe { xcb_im_status_start_callback(self.im, self.ic) };
+    }
+
+    /// Update what is displayed in the status area.
+    pub fn status_draw(&mut self, info: &StatusInfo) -> Result<(), ImeError> {
+        match info {
+            StatusInfo::Text { text, feedback } => {
+                let text = if text.is_empty() {
+                    None
+                } else {
+                    Some(CompoundText::new(text)?)
+                };
+                let mut feedback: Vec<u32> = feedback.iter().map(|f| f.bits()).collect();
+                let status = draw_status(text.is_some(), !feedback.is_empty());
+                let mut frame = xcb_im_status_draw_text_fr_t {
+                    input_method_ID: 0,
+                    input_context_ID: 0,
+                    type_: XIM_STATUS_TEXT,
+                    status,
+                    length_of_status_string: text.as_ref().map_or(0, |text| text.len as u16),
+                    status_string: text
+                        .as_ref()
+                        .map_or(std::ptr::null_mut(), |text| text.data as _),
+                    feedback_array: _xcb_im_status_draw_text_fr_t__bindgen_ty_1 {
+                        size: feedback.len() as u32,
+                        items: feedback.as_mut_ptr(),
+                    },
+                };
+                unsafe { xcb_im_status_draw_text_callback(self.im, self.ic, &mut frame) };
+            }
+            StatusInfo::Bitmap(pixmap) => {
+                let mut frame = xcb_im_status_draw_bitmap_fr_t {
+                    input_method_ID: 0,
+                    input_context_ID: 0,
+                    type_: XIM_STATUS_BITMAP,
+                    pixmap_data: pixmap.resource_id(),
+                };
+                unsafe { xcb_im_status_draw_bitmap_callback(self.im, self.ic, &mut frame) };
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the client to hide its status area.
+    pub fn status_done(&mut self) {
+        unsafe { xcb_im_status_done_callback(self.im, self.ic) };
+    }
+}
+
+/// [`ImeServer`] provides an input method for other applications, the counterpart to
+/// [`ImeClient`](crate::ImeClient).
+///
+/// The server receives the key events of the input contexts created by its clients through the
+/// callback set by [`set_forward_event_cb`] and answers them using the methods of
+/// [`ServerInputContext`].
+///
+/// [`set_forward_event_cb`]: ImeServer::set_forward_event_cb
+pub struct ImeServer {
+    conn: Option<Arc<xcb::Connection>>,
+    im: *mut xcb_im_t,
+    ics: HashMap<usize, ServerInputContext>,
+    callbacks: ServerCallbacks,
+}
+
+impl ImeServer {
+    /// Create a new [`ImeServer`] and register it as input method `name`.
+    ///
+    /// `server_win` is a window created by the application which is used to communicate with the
+    /// clients. `locales` is a comma separated list of the locales the input method supports,
+    /// for example `"en,ja"`, and `input_styles` are the input styles the input method offers to
+    /// its clients.
+    ///
+    /// Clients select this server by setting `XMODIFIERS` to `@im=name`.
+    pub fn new(
+        conn: Arc<xcb::Connection>,
+        screen_id: i32,
+        server_win: Window,
+        name: &str,
+        locales: &str,
+        input_styles: &[InputStyle],
+    ) -> Result<Pin<Box<Self>>, ImeError> {
+        let mut res =
+            unsafe { Self::unsafe_new(&conn, screen_id, server_win, name, locales, input_styles)? };
+        res.conn = Some(conn);
+        Ok(res)
+    }
+
+    /// Create a new [`ImeServer`].
+    ///
+    /// This is the same as [`new`], except that the [`xcb::Connection`] is not wrapped
+    /// into an [`Arc`].
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible to ensure that the [`ImeServer`] does not outlive the connection.
+    ///
+    /// [`Arc`]: std::sync::Arc
+    /// [`new`]: ImeServer::new
+    pub unsafe fn unsafe_new(
+        conn: &xcb::Connection,
+        screen_id: i32,
+        server_win: Window,
+        name: &str,
+        locales: &str,
+        input_styles: &[InputStyle],
+    ) -> Result<Pin<Box<Self>>, ImeError> {
+        let name = CString::new(name).map_err(|_| ImeError::Encoding)?;
+        let locales = CString::new(locales).map_err(|_| ImeError::Encoding)?;
+        let mut styles: Vec<u32> = input_styles.iter().map(|style| style.bits()).collect();
+        let styles = xcb_im_styles_t {
+            nStyles: styles.len() as u32,
+            styles: styles.as_mut_ptr(),
+        };
+        let mut compound_text = *b"COMPOUND_TEXT\0";
+        let mut encoding_list = [compound_text.as_mut_ptr() as xcb_im_encoding_t];
+        let encodings = xcb_im_encodings_t {
+            nEncodings: encoding_list.len() as _,
+            encodings: encoding_list.as_mut_ptr(),
+        };
+        xcb_compound_text_init();
+        let mut res = Box::pin(Self {
+            conn: None,
+            im: std::ptr::null_mut(),
+            ics: HashMap::new(),
+            callbacks: ServerCallbacks::default(),
+        });
+        let data: *mut Self = res.as_mut().get_mut();
+        let im = xcb_im_create(
+            conn.get_raw_conn() as _,
+            screen_id,
+            server_win.resource_id(),
+            name.as_ptr(),
+            locales.as_ptr(),
+            &styles,
+            std::ptr::null(),
+            std::ptr::null(),
+            &encodings,
+            (EventMask::KEY_PRESS | EventMask::KEY_RELEASE).bits(),
+            Some(server_callback),
+            data as _,
+        );
+        if im.is_null() {
+            return Err(ImeError::OpenFailed);
+        }
+        res.im = im;
+        xcb_im_set_log_handler(im, Some(xcb_log_wrapper));
+        if !xcb_im_open_im(im) {
+            return Err(ImeError::OpenFailed);
+        }
+        Ok(res)
+    }
+
+    fn insert_ic(&mut self, ic: *mut xcb_im_input_context_t) {
+        let id = ic as usize;
+        let data = Box::new(InputContextData {
+            server: self as *mut Self,
+            id,
+        });
+        unsafe {
+            xcb_im_input_context_set_data(
+                ic,
+                Box::into_raw(data) as _,
+                Some(free_input_context_data),
+            )
+        };
+        self.ics.insert(
+            id,
+            ServerInputContext {
+                im: self.im,
+                ic,
+                user_data: None,
+            },
+        );
+    }
+
+    /// Let the IME server process XCB's events.
+    ///
+    /// Return `true` if the event has been handled by the server. This method should be called
+    /// on **any** event from the event queue.
+    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
+        unsafe { xcb_im_filter_event(self.im, event.as_raw() as _) }
+    }
+
+    /// Get the [`ServerInputContext`] with the identifier `id`.
+    pub fn input_context(&self, id: usize) -> Option<&ServerInputContext> {
+        self.ics.get(&id)
+    }
+
+    /// Get the [`ServerInputContext`] with the identifier `id` mutably, for example to commit a
+    /// string outside of the callbacks.
+    pub fn input_context_mut(&mut self, id: usize) -> Option<&mut ServerInputContext> {
+        self.ics.get_mut(&id)
+    }
+
+    /// Set callback to be called once a client connected to the server.
+    pub fn set_connect_cb<F>(&mut self, f: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.callbacks.connect = Some(Box::new(f));
+    }
+
+    /// Set callback to be called once a client created an input context.
+    pub fn set_create_ic_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut ServerInputContext) + 'static,
+    {
+        self.callbacks.create_ic = Some(Box::new(f));
+    }
+
+    /// Set callback to be called before a client destroys an input context.
+    pub fn set_destroy_ic_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut ServerInputContext) + 'static,
+    {
+        self.callbacks.destroy_ic = Some(Box::new(f));
+    }
+
+    /// Set callback to be called if an input context gains or loses the focus.
+    ///
+    /// The second argument is `true` if the input context gained the focus.
+    pub fn set_focus_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut ServerInputContext, bool) + 'static,
+    {
+        self.callbacks.focus = Some(Box::new(f));
+    }
+
+    /// Set callback to be called for every key event a client sends to the server.
+    ///
+    /// The callback returns `true` if the input method used the event. Otherwise the event is
+    /// sent back to the client, which is also done for all events if no callback is set.
+    pub fn set_forward_event_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(&mut ServerInputContext, &'a xcb::Event) -> bool + 'static,
+    {
+        self.callbacks.forward_event = Some(Box::new(f));
+    }
+
+    /// Set callback to be called if a client resets an input context.
+    ///
+    /// The input method should discard its composition state. The callback returns the preedit
+    /// text that was pending, which is passed on to the client, or an empty string.
+    pub fn set_reset_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut ServerInputContext) -> String + 'static,
+    {
+        self.callbacks.reset = Some(Box::new(f));
+    }
+}
+
+impl Drop for ImeServer {
+    fn drop(&mut self) {
+        if self.im.is_null() {
+            return;
+        }
+        unsafe {
+            xcb_im_close_im(self.im);
+            xcb_im_destroy(self.im);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTRS: [u32; 8] = [
+        ATTR_AREA,
+        ATTR_AREA_NEEDED,
+        ATTR_SPOT_LOCATION,
+        ATTR_COLORMAP,
+        ATTR_FOREGROUND,
+        ATTR_BACKGROUND,
+        ATTR_BACKGROUND_PIXMAP,
+        ATTR_LINE_SPACE,
+    ];
+
+    fn raw_attributes() -> xcb_im_preedit_attr_t {
+        xcb_im_preedit_attr_t {
+            area: xcb_rectangle_t {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+            },
+            area_needed: xcb_rectangle_t {
+                x: 5,
+                y: 6,
+                width: 7,
+                height: 8,
+            },
+            spot_location: xcb_point_t { x: 9, y: -10 },
+            colormap: 11,
+            foreground: 12,
+            background: 13,
+            bg_pixmap: 14,
+            line_space: 15,
+        }
+    }
+
+    /// Attributes set in `attrs` as `(area, area_needed, colormap, foreground, background,
+    /// background_pixmap, line_space)`, areas by their x coordinate.
+    fn set_attributes(attrs: &PreeditAttributes) -> [Option<u32>; 7] {
+        [
+            attrs.area.map(|area| area.x as u32),
+            attrs.area_needed.map(|area| area.x as u32),
+            attrs.colormap.map(|colormap| colormap.resource_id()),
+            attrs.foreground,
+            attrs.background,
+            attrs.background_pixmap.map(|pixmap| pixmap.resource_id()),
+            attrs.line_space,
+        ]
+    }
+
+    #[test]
+    fn string_length_limit() {
+        assert_eq!(string_length(0), Ok(0));
+        assert_eq!(string_length(u16::MAX as usize), Ok(u16::MAX));
+        assert_eq!(
+            string_length(u16::MAX as usize + 1),
+            Err(ImeError::Encoding)
+        );
+    }
+
+    #[test]
+    fn draw_status_flags() {
+        assert_eq!(draw_status(true, true), 0);
+        assert_eq!(draw_status(false, true), 1);
+        assert_eq!(draw_status(true, false), 2);
+        assert_eq!(draw_status(false, false), 3);
+    }
+
+    #[test]
+    fn attribute_mask_bits() {
+        for (i, bit) in ATTRS.iter().enumerate() {
+            assert_eq!(bit.count_ones(), 1);
+            assert!(!ATTRS[..i].contains(bit));
+        }
+
+        let attr = raw_attributes();
+        assert_eq!(set_attributes(&attributes_from_raw(&attr, 0)), [None; 7]);
+        assert_eq!(spot_location_from_raw(&attr, 0), None);
+        let all = ATTRS.iter().fold(0, |mask, bit| mask | bit);
+        let expected = [1, 5, 11, 12, 13, 14, 15].map(Some);
+        assert_eq!(set_attributes(&attributes_from_raw(&attr, all)), expected);
+        assert_eq!(spot_location_from_raw(&attr, all), Some((9, -10)));
+
+        // each bit sets exactly one attribute
+        let bits = [
+            ATTR_AREA,
+            ATTR_AREA_NEEDED,
+            ATTR_COLORMAP,
+            ATTR_FOREGROUND,
+            ATTR_BACKGROUND,
+            ATTR_BACKGROUND_PIXMAP,
+            ATTR_LINE_SPACE,
+        ];
+        for (i, bit) in bits.iter().enumerate() {
+            let mut expected = [None; 7];
+            expected[i] = Some([1, 5, 11, 12, 13, 14, 15][i]);
+            assert_eq!(set_attributes(&attributes_from_raw(&attr, *bit)), expected);
+            assert_eq!(spot_location_from_raw(&attr, *bit), None);
+        }
+        let attrs = attributes_from_raw(&attr, ATTR_SPOT_LOCATION);
+        assert_eq!(set_attributes(&attrs), [None; 7]);
+        assert_eq!(
+            spot_location_from_raw(&attr, ATTR_SPOT_LOCATION),
+            Some((9, -10))
+        );
+    }
+}
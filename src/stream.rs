@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use crate::{EventUse, ImeClient, ImeEvent, ImeHandler};
+
+/// Event yielded by [`ImeEventStream`].
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// Event of the IME.
+    Ime(ImeEvent),
+    /// Event of the X server the [`ImeClient`] did not handle, see [`ImeClient::process_event`].
+    ///
+    /// Messages of the XIM protocol are never yielded.
+    X(xcb::Event),
+}
+
+/// File descriptor of the connection to the X server, which stays owned by the connection.
+struct ConnectionFd(RawFd);
+
+impl AsRawFd for ConnectionFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// [`Stream`] of the events of an [`ImeClient`] for applications running on Tokio.
+///
+/// The stream waits until the connection to the X server becomes readable, passes every event to
+/// [`ImeClient::process_event`] and yields the [`ImeEvent`]s this caused, followed by the events
+/// the [`ImeClient`] did not handle. Callbacks and the [`ImeHandler`] of the [`ImeClient`] are
+/// still called.
+///
+/// The stream takes over reading events from the connection, events must not be read from it
+/// elsewhere. Once the connection to the X server has failed, the error is yielded and the
+/// stream ends.
+pub struct ImeEventStream<H: ImeHandler = ()> {
+    conn: Arc<xcb::Connection>,
+    fd: AsyncFd<ConnectionFd>,
+    ime: Pin<Box<ImeClient<H>>>,
+    pending: VecDeque<StreamEvent>,
+    is_terminated: bool,
+}
+
+impl<H: ImeHandler> ImeEventStream<H> {
+    /// Create a stream reading the events of the connection `ime` has been created with.
+    ///
+    /// Fail if `ime` has been created by [`ImeClient::unsafe_new`], which does not keep the
+    /// connection, or if the file descriptor of the connection can not be registered with the
+    /// Tokio reactor, this happens in particular if called outside of a Tokio runtime.
+    pub fn new(mut ime: Pin<Box<ImeClient<H>>>) -> io::Result<Self> {
+        let conn = match &ime.conn {
+            Some(conn) => Arc::clone(conn),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the IME client does not own its connection",
+                ))
+            }
+        };
+        let fd = AsyncFd::with_interest(ConnectionFd(conn.as_raw_fd()), Interest::READABLE)?;
+        ime.set_event_queue(true);
+        Ok(Self {
+            conn,
+            fd,
+            ime,
+            pending: VecDeque::new(),
+            is_terminated: false,
+        })
+    }
+
+    /// The [`ImeClient`] driven by this stream.
+    pub fn ime(&self) -> &ImeClient<H> {
+        &self.ime
+    }
+
+    /// The [`ImeClient`] driven by this stream, for example to update the position of the IME
+    /// window.
+    pub fn ime_mut(&mut self) -> &mut ImeClient<H> {
+        &mut self.ime
+    }
+
+    /// Stop driving the [`ImeClient`] and return it, events not yet yielded are dropped.
+    pub fn into_inner(mut self) -> Pin<Box<ImeClient<H>>> {
+        self.ime.set_event_queue(false);
+        self.ime
+    }
+
+    fn process_event(&mut self, event: xcb::Event) {
+        if self.ime.handle_event(&event) == EventUse::Ignored {
+            self.pending.push_back(StreamEvent::X(event));
+        }
+        let events = self.ime.drain_events().map(StreamEvent::Ime);
+        self.pending.extend(events);
+    }
+}
+
+impl<H: ImeHandler> Stream for ImeEventStream<H> {
+    type Item = xcb::Result<StreamEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            // events queued outside of `process_event`, e.g. by `update_pos`
+            let events = this.ime.drain_events().map(StreamEvent::Ime);
+            this.pending.extend(events);
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if this.is_terminated {
+                return Poll::Ready(None);
+            }
+            // xcb may have read events into its queue while waiting for a reply, so the queue is
+            // drained before waiting for the connection to become readable
+            match this.conn.poll_for_event() {
+                Ok(Some(event)) => {
+                    this.process_event(event);
+                    continue;
+                }
+                Ok(None) => {}
+                Err(err @ xcb::Error::Protocol(_)) => return Poll::Ready(Some(Err(err))),
+                Err(err @ xcb::Error::Connection(_)) => {
+                    this.is_terminated = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+            // requests sent while processing events, e.g. forwarded key events, must reach the X
+            // server before waiting for its answer
+            if let Err(err) = this.conn.flush() {
+                this.is_terminated = true;
+                return Poll::Ready(Some(Err(xcb::Error::Connection(err))));
+            }
+            match this.fd.poll_read_ready(cx) {
+                Poll::Ready(Ok(mut guard)) => guard.clear_ready(),
+                Poll::Ready(Err(_)) => {
+                    this.is_terminated = true;
+                    let err = xcb::Error::Connection(xcb::ConnError::Connection);
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,807 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::os::raw::c_void;
+
+use xcb::x::{Atom, Colormap, EventMask, Pixmap, Point, Rectangle, Window};
+use xcb::{Xid, XidNew};
+
+use crate::clib::*;
+use crate::{ImeError, InputStyle};
+
+/// Values of the IME server, see [`ImeClient::query_im_values`].
+///
+/// [`ImeClient::query_im_values`]: crate::ImeClient::query_im_values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImValues {
+    /// Input styles supported by the IME server.
+    pub input_styles: Vec<InputStyle>,
+    /// Encoding of the strings exchanged with the IME server.
+    pub encoding: Encoding,
+    /// Extensions of the XIM protocol supported by the IME server.
+    pub extensions: Vec<XimExtension>,
+    /// Name under which the IME server registered itself, taken from the `@server=` atom in
+    /// `XIM_SERVERS` that was used to connect to it, for example `fcitx` or `ibus`.
+    ///
+    /// `None` if the name could not be determined.
+    pub server_name: Option<String>,
+    /// Forward event mask of the last `XIM_SET_EVENT_MASK` the IME server sent without naming
+    /// an input context, keypress and keyrelease events if it has not sent one.
+    ///
+    /// Input contexts start out with this mask, a mask sent for a specific input context is
+    /// reported by [`InputContext::forward_event_mask`] instead.
+    ///
+    /// [`InputContext::forward_event_mask`]: crate::InputContext::forward_event_mask
+    pub forward_event_mask: EventMask,
+}
+
+/// Encoding of the strings exchanged with the IME server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// `COMPOUND_TEXT`, converted from and to UTF-8 by this crate.
+    CompoundText,
+    /// `UTF8_STRING`.
+    Utf8,
+}
+
+/// Extension of the XIM protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XimExtension {
+    /// `XIM_EXT_SET_EVENT_MASK`, the IME server tells which events to forward to it.
+    SetEventMask,
+    /// `XIM_EXT_FORWARD_KEYEVENT`, key events are forwarded in a compact form.
+    ForwardKeyEvent,
+    /// `XIM_EXT_MOVE`, the position of the IME window is updated without a reply.
+    Move,
+}
+
+impl XimExtension {
+    pub(crate) const ALL: [XimExtension; 3] = [
+        XimExtension::SetEventMask,
+        XimExtension::ForwardKeyEvent,
+        XimExtension::Move,
+    ];
+
+    /// Major and minor opcode of the extension.
+    pub(crate) fn codes(self) -> (u16, u16) {
+        let minor = match self {
+            XimExtension::SetEventMask => XCB_XIM_EXT_SET_EVENT_MASK,
+            XimExtension::ForwardKeyEvent => XCB_XIM_EXT_FORWARD_KEYEVENT,
+            XimExtension::Move => XCB_XIM_EXT_MOVE,
+        };
+        (XCB_XIM_EXTENSION as u16, minor as u16)
+    }
+}
+
+/// Attribute of an input context, see [`ImeClient::set_ic_values`].
+///
+/// Attributes of the preedit and the status area are wrapped in [`IcAttribute::Preedit`] and
+/// [`IcAttribute::Status`] and sent to the IME as nested lists.
+///
+/// [`ImeClient::set_ic_values`]: crate::ImeClient::set_ic_values
+#[derive(Debug, Clone)]
+pub enum IcAttribute {
+    /// Input style, it can not be changed once the input context has been created.
+    InputStyle(InputStyle),
+    /// Window the input context belongs to.
+    ClientWindow(Window),
+    /// Window that has the focus, the IME draws relative to it.
+    FocusWindow(Window),
+    /// Events the IME wants to be passed to it, this attribute can only be read.
+    FilterEvents(EventMask),
+    /// Attribute of the preedit area.
+    Preedit(AreaAttribute),
+    /// Attribute of the status area.
+    Status(AreaAttribute),
+}
+
+/// Kind of an [`IcAttribute`], see [`ImeClient::get_ic_values`].
+///
+/// [`ImeClient::get_ic_values`]: crate::ImeClient::get_ic_values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IcAttributeKind {
+    /// See [`IcAttribute::InputStyle`].
+    InputStyle,
+    /// See [`IcAttribute::ClientWindow`].
+    ClientWindow,
+    /// See [`IcAttribute::FocusWindow`].
+    FocusWindow,
+    /// See [`IcAttribute::FilterEvents`].
+    FilterEvents,
+    /// Attribute of the preedit area, see [`IcAttribute::Preedit`].
+    Preedit(AreaAttributeKind),
+    /// Attribute of the status area, see [`IcAttribute::Status`].
+    Status(AreaAttributeKind),
+}
+
+/// Attribute of the preedit or status area of an input context.
+///
+/// The attributes correspond to the fields of [`PreeditAttributes`], plus the position of the IME
+/// window and the standard colormap.
+///
+/// [`PreeditAttributes`]: crate::PreeditAttributes
+#[derive(Debug, Clone)]
+pub enum AreaAttribute {
+    /// Area in which the IME should draw.
+    Area(Rectangle),
+    /// Area the IME would like to use.
+    AreaNeeded(Rectangle),
+    /// Position of the IME window, only used for the preedit area.
+    SpotLocation(Point),
+    /// Colormap to use for drawing.
+    Colormap(Colormap),
+    /// Property of the standard colormap to use for drawing.
+    StdColormap(Atom),
+    /// Foreground pixel value.
+    Foreground(u32),
+    /// Background pixel value.
+    Background(u32),
+    /// Background pixmap.
+    BackgroundPixmap(Pixmap),
+    /// Base font name list of the font set to draw text with.
+    FontSet(String),
+    /// Distance between two lines of text in pixels.
+    LineSpace(u32),
+}
+
+/// Kind of an [`AreaAttribute`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AreaAttributeKind {
+    /// See [`AreaAttribute::Area`].
+    Area,
+    /// See [`AreaAttribute::AreaNeeded`].
+    AreaNeeded,
+    /// See [`AreaAttribute::SpotLocation`].
+    SpotLocation,
+    /// See [`AreaAttribute::Colormap`].
+    Colormap,
+    /// See [`AreaAttribute::StdColormap`].
+    StdColormap,
+    /// See [`AreaAttribute::Foreground`].
+    Foreground,
+    /// See [`AreaAttribute::Background`].
+    Background,
+    /// See [`AreaAttribute::BackgroundPixmap`].
+    BackgroundPixmap,
+    /// See [`AreaAttribute::FontSet`].
+    FontSet,
+    /// See [`AreaAttribute::LineSpace`].
+    LineSpace,
+}
+
+/// Value of an attribute in the representation xcb-imdkit expects.
+pub(crate) enum XimValue {
+    Card32(u32),
+    Point(xcb_point_t),
+    Rectangle(Rectangle),
+    String(CString),
+}
+
+impl XimValue {
+    pub(crate) fn as_ptr(&self) -> *const c_void {
+        match self {
+            XimValue::Card32(v) => v as *const u32 as _,
+            XimValue::Point(p) => p as *const xcb_point_t as _,
+            XimValue::Rectangle(r) => r as *const Rectangle as _,
+            // xcb-imdkit expects the string itself rather than a pointer to it
+            XimValue::String(s) => s.as_ptr() as _,
+        }
+    }
+}
+
+impl IcAttribute {
+    /// Kind of the attribute.
+    pub fn kind(&self) -> IcAttributeKind {
+        match self {
+            IcAttribute::InputStyle(_) => IcAttributeKind::InputStyle,
+            IcAttribute::ClientWindow(_) => IcAttributeKind::ClientWindow,
+            IcAttribute::FocusWindow(_) => IcAttributeKind::FocusWindow,
+            IcAttribute::FilterEvents(_) => IcAttributeKind::FilterEvents,
+            IcAttribute::Preedit(attr) => IcAttributeKind::Preedit(attr.kind()),
+            IcAttribute::Status(attr) => IcAttributeKind::Status(attr.kind()),
+        }
+    }
+
+    /// Value of the attribute, that of the nested attribute for the preedit and status area.
+    pub(crate) fn xim_value(&self) -> Result<XimValue, ImeError> {
+        Ok(match self {
+            IcAttribute::InputStyle(style) => XimValue::Card32(style.bits()),
+            IcAttribute::ClientWindow(win) | IcAttribute::FocusWindow(win) => {
+                XimValue::Card32(win.resource_id())
+            }
+            IcAttribute::FilterEvents(mask) => XimValue::Card32(mask.bits()),
+            IcAttribute::Preedit(attr) | IcAttribute::Status(attr) => attr.xim_value()?,
+        })
+    }
+
+    fn decode(kind: IcAttributeKind, value: &[u8]) -> Option<Self> {
+        Some(match kind {
+            IcAttributeKind::InputStyle => {
+                IcAttribute::InputStyle(InputStyle::from_bits_truncate(card32(value)?))
+            }
+            IcAttributeKind::ClientWindow => IcAttribute::ClientWindow(Window::new(card32(value)?)),
+            IcAttributeKind::FocusWindow => IcAttribute::FocusWindow(Window::new(card32(value)?)),
+            IcAttributeKind::FilterEvents => {
+                IcAttribute::FilterEvents(EventMask::from_bits_truncate(card32(value)?))
+            }
+            IcAttributeKind::Preedit(kind) => {
+                IcAttribute::Preedit(AreaAttribute::decode(kind, value)?)
+            }
+            IcAttributeKind::Status(kind) => {
+                IcAttribute::Status(AreaAttribute::decode(kind, value)?)
+            }
+        })
+    }
+}
+
+impl IcAttributeKind {
+    pub(crate) fn name(self) -> &'static [u8] {
+        match self {
+            IcAttributeKind::InputStyle => XCB_XIM_XNInputStyle,
+            IcAttributeKind::ClientWindow => XCB_XIM_XNClientWindow,
+            IcAttributeKind::FocusWindow => XCB_XIM_XNFocusWindow,
+            IcAttributeKind::FilterEvents => XCB_XIM_XNFilterEvents,
+            IcAttributeKind::Preedit(_) => XCB_XIM_XNPreeditAttributes,
+            IcAttributeKind::Status(_) => XCB_XIM_XNStatusAttributes,
+        }
+    }
+}
+
+impl AreaAttribute {
+    /// Kind of the attribute.
+    pub fn kind(&self) -> AreaAttributeKind {
+        match self {
+            AreaAttribute::Area(_) => AreaAttributeKind::Area,
+            AreaAttribute::AreaNeeded(_) => AreaAttributeKind::AreaNeeded,
+            AreaAttribute::SpotLocation(_) => AreaAttributeKind::SpotLocation,
+            AreaAttribute::Colormap(_) => AreaAttributeKind::Colormap,
+            AreaAttribute::StdColormap(_) => AreaAttributeKind::StdColormap,
+            AreaAttribute::Foreground(_) => AreaAttributeKind::Foreground,
+            AreaAttribute::Background(_) => AreaAttributeKind::Background,
+            AreaAttribute::BackgroundPixmap(_) => AreaAttributeKind::BackgroundPixmap,
+            AreaAttribute::FontSet(_) => AreaAttributeKind::FontSet,
+            AreaAttribute::LineSpace(_) => AreaAttributeKind::LineSpace,
+        }
+    }
+
+    pub(crate) fn xim_value(&self) -> Result<XimValue, ImeError> {
+        Ok(match self {
+            AreaAttribute::Area(rect) | AreaAttribute::AreaNeeded(rect) => {
+                XimValue::Rectangle(*rect)
+            }
+            AreaAttribute::SpotLocation(point) => XimValue::Point(xcb_point_t {
+                x: point.x,
+                y: point.y,
+            }),
+            AreaAttribute::Colormap(colormap) => XimValue::Card32(colormap.resource_id()),
+            AreaAttribute::StdColormap(atom) => XimValue::Card32(atom.resource_id()),
+            AreaAttribute::Foreground(pixel) | AreaAttribute::Background(pixel) => {
+                XimValue::Card32(*pixel)
+            }
+            AreaAttribute::BackgroundPixmap(pixmap) => XimValue::Card32(pixmap.resource_id()),
+            AreaAttribute::FontSet(font_set) => {
+                XimValue::String(CString::new(font_set.as_str()).map_err(|_| ImeError::Encoding)?)
+            }
+            AreaAttribute::LineSpace(line_space) => XimValue::Card32(*line_space),
+        })
+    }
+
+    fn decode(kind: AreaAttributeKind, value: &[u8]) -> Option<Self> {
+        Some(match kind {
+            AreaAttributeKind::Area => AreaAttribute::Area(rectangle(value)?),
+            AreaAttributeKind::AreaNeeded => AreaAttribute::AreaNeeded(rectangle(value)?),
+            AreaAttributeKind::SpotLocation => AreaAttribute::SpotLocation(Point {
+                x: i16::from_ne_bytes(value.get(0..2)?.try_into().ok()?),
+                y: i16::from_ne_bytes(value.get(2..4)?.try_into().ok()?),
+            }),
+            AreaAttributeKind::Colormap => AreaAttribute::Colormap(Colormap::new(card32(value)?)),
+            AreaAttributeKind::StdColormap => AreaAttribute::StdColormap(Atom::new(card32(value)?)),
+            AreaAttributeKind::Foreground => AreaAttribute::Foreground(card32(value)?),
+            AreaAttributeKind::Background => AreaAttribute::Background(card32(value)?),
+            AreaAttributeKind::BackgroundPixmap => {
+                AreaAttribute::BackgroundPixmap(Pixmap::new(card32(value)?))
+            }
+            AreaAttributeKind::FontSet => {
+                // the base font name list is preceded by its length
+                let len = card16(value)? as usize;
+                let names = value.get(2..2 + len)?;
+                AreaAttribute::FontSet(String::from_utf8_lossy(names).into_owned())
+            }
+            AreaAttributeKind::LineSpace => AreaAttribute::LineSpace(card32(value)?),
+        })
+    }
+}
+
+impl AreaAttributeKind {
+    pub(crate) fn name(self) -> &'static [u8] {
+        match self {
+            AreaAttributeKind::Area => XCB_XIM_XNArea,
+            AreaAttributeKind::AreaNeeded => XCB_XIM_XNAreaNeeded,
+            AreaAttributeKind::SpotLocation => XCB_XIM_XNSpotLocation,
+            AreaAttributeKind::Colormap => XCB_XIM_XNColormap,
+            AreaAttributeKind::StdColormap => XCB_XIM_XNStdColormap,
+            AreaAttributeKind::Foreground => XCB_XIM_XNForeground,
+            AreaAttributeKind::Background => XCB_XIM_XNBackground,
+            AreaAttributeKind::BackgroundPixmap => XCB_XIM_XNBackgroundPixmap,
+            AreaAttributeKind::FontSet => XCB_XIM_XNFontSet,
+            AreaAttributeKind::LineSpace => XCB_XIM_XNLineSpace,
+        }
+    }
+}
+
+// Values are sent in the byte order of the client, which xcb-imdkit announces as the native one
+// when connecting to the IME server.
+fn card16(value: &[u8]) -> Option<u16> {
+    Some(u16::from_ne_bytes(value.get(0..2)?.try_into().ok()?))
+}
+
+fn card32(value: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(value.get(0..4)?.try_into().ok()?))
+}
+
+fn rectangle(value: &[u8]) -> Option<Rectangle> {
+    Some(Rectangle {
+        x: card16(value)? as i16,
+        y: card16(value.get(2..)?)? as i16,
+        width: card16(value.get(4..)?)?,
+        height: card16(value.get(6..)?)?,
+    })
+}
+
+type NestedQuery<'a> = (
+    &'static [u8],
+    fn(AreaAttribute) -> IcAttribute,
+    &'a Vec<AreaAttributeKind>,
+);
+
+/// Attributes queried by [`query_names`] without duplicates, the attributes of the preedit and
+/// status area are moved into a nested list each after the other attributes.
+struct Query {
+    top: Vec<IcAttributeKind>,
+    preedit: Vec<AreaAttributeKind>,
+    status: Vec<AreaAttributeKind>,
+}
+
+impl Query {
+    fn new(kinds: &[IcAttributeKind]) -> Self {
+        let mut query = Query {
+            top: vec![],
+            preedit: vec![],
+            status: vec![],
+        };
+        for (i, &kind) in kinds.iter().enumerate() {
+            if kinds[..i].contains(&kind) {
+                continue;
+            }
+            match kind {
+                IcAttributeKind::Preedit(kind) => query.preedit.push(kind),
+                IcAttributeKind::Status(kind) => query.status.push(kind),
+                kind => query.top.push(kind),
+            }
+        }
+        query
+    }
+
+    /// Name of the nested lists, how to wrap their values and the attributes queried in them.
+    fn nested(&self) -> [NestedQuery<'_>; 2] {
+        [
+            (
+                XCB_XIM_XNPreeditAttributes,
+                IcAttribute::Preedit,
+                &self.preedit,
+            ),
+            (
+                XCB_XIM_XNStatusAttributes,
+                IcAttribute::Status,
+                &self.status,
+            ),
+        ]
+    }
+}
+
+/// Names to pass to `xcb_xim_get_ic_values` to query the attributes `kinds`.
+pub(crate) fn query_names(kinds: &[IcAttributeKind]) -> Vec<*const u8> {
+    let query = Query::new(kinds);
+    let mut names: Vec<_> = query.top.iter().map(|kind| kind.name().as_ptr()).collect();
+    for (name, _, kinds) in query.nested() {
+        if kinds.is_empty() {
+            continue;
+        }
+        names.push(name.as_ptr());
+        names.extend(kinds.iter().map(|kind| kind.name().as_ptr()));
+        names.push(XCB_XIM_XNSeparatorofNestedList.as_ptr());
+    }
+    names
+}
+
+/// Attribute queried at the top level of `xcb_xim_get_ic_values`.
+#[derive(Clone, Copy)]
+enum Queried<'a> {
+    Attribute(IcAttributeKind),
+    Nested(fn(AreaAttribute) -> IcAttribute, &'a [AreaAttributeKind]),
+}
+
+/// Decode the values of the attributes `kinds` queried by the names returned by
+/// [`query_names`].
+///
+/// `attr_names` maps the IDs the IME server assigned to the attributes to their names, it is
+/// used to match each value to the attribute it belongs to. If the IDs are unknown, the values
+/// are matched by the order they were requested in, which is only done if the IME server replied
+/// a value for every attribute. Values that can not be matched or decoded are skipped.
+pub(crate) unsafe fn values_from_raw(
+    kinds: &[IcAttributeKind],
+    reply: &xcb_im_get_ic_values_reply_fr_t,
+    attr_names: &HashMap<u16, Vec<u8>>,
+) -> Vec<IcAttribute> {
+    let items = if reply.ic_attribute.items.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(reply.ic_attribute.items, reply.ic_attribute.size as usize)
+    };
+    let items: Vec<_> = items
+        .iter()
+        .map(|item| {
+            let value = if item.value.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(item.value, item.value_length as usize)
+            };
+            (item.attribute_ID, value)
+        })
+        .collect();
+    let query = Query::new(kinds);
+    let mut queried: Vec<_> = query
+        .top
+        .iter()
+        .map(|&kind| (kind.name(), Queried::Attribute(kind)))
+        .collect();
+    for (name, wrap, kinds) in query.nested() {
+        if !kinds.is_empty() {
+            queried.push((name, Queried::Nested(wrap, kinds)));
+        }
+    }
+    let matcher = Matcher::new(attr_names, items.len(), queried.len());
+    let mut values = vec![];
+    for (i, (id, value)) in items.into_iter().enumerate() {
+        match matcher.find(&queried, i, id) {
+            Some(Queried::Attribute(kind)) => values.extend(IcAttribute::decode(kind, value)),
+            Some(Queried::Nested(wrap, kinds)) => {
+                let nested: Vec<_> = NestedValues(value).collect();
+                let kinds: Vec<_> = kinds.iter().map(|&kind| (kind.name(), kind)).collect();
+                let matcher = Matcher::new(attr_names, nested.len(), kinds.len());
+                for (j, (id, value)) in nested.into_iter().enumerate() {
+                    if let Some(kind) = matcher.find(&kinds, j, id) {
+                        values.extend(AreaAttribute::decode(kind, value).map(wrap));
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+    values
+}
+
+/// Matches replied values to the queried attributes, see [`values_from_raw`].
+struct Matcher<'a> {
+    attr_names: &'a HashMap<u16, Vec<u8>>,
+    complete: bool,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(attr_names: &'a HashMap<u16, Vec<u8>>, replied: usize, queried: usize) -> Self {
+        Matcher {
+            attr_names,
+            complete: replied == queried,
+        }
+    }
+
+    /// Attribute of the value at `idx` with the attribute ID `id` among `queried`, which are
+    /// paired with their name and in the order they were requested in.
+    fn find<T: Copy>(&self, queried: &[(&[u8], T)], idx: usize, id: u16) -> Option<T> {
+        if self.attr_names.is_empty() {
+            return queried
+                .get(idx)
+                .filter(|_| self.complete)
+                .map(|&(_, attr)| attr);
+        }
+        let name = self.attr_names.get(&id)?;
+        queried
+            .iter()
+            .find(|(queried, _)| queried.strip_suffix(b"\0").unwrap_or(queried) == &name[..])
+            .map(|&(_, attr)| attr)
+    }
+}
+
+/// Iterator over the attribute IDs and values of a nested list, each value is prefixed by its ID
+/// and length and padded to four bytes.
+struct NestedValues<'a>(&'a [u8]);
+
+impl<'a> Iterator for NestedValues<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = card16(self.0)?;
+        let len = card16(self.0.get(2..)?)? as usize;
+        let value = self.0.get(4..4 + len)?;
+        let padded = 4 + ((len + 3) & !3);
+        self.0 = self.0.get(padded..).unwrap_or(&[]);
+        Some((id, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT_STYLE: u16 = 1;
+    const CLIENT_WINDOW: u16 = 2;
+    const FOCUS_WINDOW: u16 = 3;
+    const PREEDIT: u16 = 4;
+    const STATUS: u16 = 5;
+    const AREA: u16 = 10;
+    const SPOT_LOCATION: u16 = 11;
+    const FOREGROUND: u16 = 12;
+    const FONT_SET: u16 = 13;
+    const LINE_SPACE: u16 = 14;
+
+    fn attr_names() -> HashMap<u16, Vec<u8>> {
+        let names: [(u16, &[u8]); 10] = [
+            (INPUT_STYLE, XCB_XIM_XNInputStyle),
+            (CLIENT_WINDOW, XCB_XIM_XNClientWindow),
+            (FOCUS_WINDOW, XCB_XIM_XNFocusWindow),
+            (PREEDIT, XCB_XIM_XNPreeditAttributes),
+            (STATUS, XCB_XIM_XNStatusAttributes),
+            (AREA, XCB_XIM_XNArea),
+            (SPOT_LOCATION, XCB_XIM_XNSpotLocation),
+            (FOREGROUND, XCB_XIM_XNForeground),
+            (FONT_SET, XCB_XIM_XNFontSet),
+            (LINE_SPACE, XCB_XIM_XNLineSpace),
+        ];
+        names
+            .iter()
+            .map(|&(id, name)| (id, name[..name.len() - 1].to_vec()))
+            .collect()
+    }
+
+    fn card32(value: u32) -> Vec<u8> {
+        value.to_ne_bytes().to_vec()
+    }
+
+    fn card16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    fn font_set(names: &str) -> Vec<u8> {
+        let mut value = card16s(&[names.len() as u16]);
+        value.extend_from_slice(names.as_bytes());
+        value
+    }
+
+    /// Nested list of `values`, each prefixed by its ID and length and padded to four bytes.
+    fn nested(values: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut list = vec![];
+        for (id, value) in values {
+            list.extend(card16s(&[*id, value.len() as u16]));
+            list.extend_from_slice(value);
+            list.resize((list.len() + 3) & !3, 0);
+        }
+        list
+    }
+
+    fn decode(
+        kinds: &[IcAttributeKind],
+        items: &[(u16, Vec<u8>)],
+        attr_names: &HashMap<u16, Vec<u8>>,
+    ) -> Vec<IcAttribute> {
+        let mut raw: Vec<_> = items
+            .iter()
+            .map(|(id, value)| xcb_im_xicattribute_fr_t {
+                attribute_ID: *id,
+                value_length: value.len() as u16,
+                value: value.as_ptr() as *mut u8,
+            })
+            .collect();
+        let reply = xcb_im_get_ic_values_reply_fr_t {
+            input_method_ID: 0,
+            input_context_ID: 0,
+            ic_attribute: _xcb_im_get_ic_values_reply_fr_t__bindgen_ty_1 {
+                size: raw.len() as u32,
+                items: raw.as_mut_ptr(),
+            },
+        };
+        unsafe { values_from_raw(kinds, &reply, attr_names) }
+    }
+
+    // the attributes do not implement `PartialEq` as `xcb::x::Rectangle` does not
+    fn assert_values(values: Vec<IcAttribute>, expected: &[IcAttribute]) {
+        assert_eq!(format!("{:?}", values), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn match_by_id() {
+        let kinds = [IcAttributeKind::InputStyle, IcAttributeKind::ClientWindow];
+        let items = [
+            (CLIENT_WINDOW, card32(42)),
+            (INPUT_STYLE, card32(InputStyle::PREEDIT_CALLBACKS.bits())),
+        ];
+        assert_values(
+            decode(&kinds, &items, &attr_names()),
+            &[
+                IcAttribute::ClientWindow(Window::new(42)),
+                IcAttribute::InputStyle(InputStyle::PREEDIT_CALLBACKS),
+            ],
+        );
+    }
+
+    #[test]
+    fn nested_lists() {
+        let kinds = [
+            IcAttributeKind::Preedit(AreaAttributeKind::SpotLocation),
+            IcAttributeKind::Status(AreaAttributeKind::Foreground),
+            IcAttributeKind::FocusWindow,
+            IcAttributeKind::Preedit(AreaAttributeKind::Area),
+        ];
+        let items = [
+            (FOCUS_WINDOW, card32(7)),
+            (
+                PREEDIT,
+                nested(&[
+                    (AREA, card16s(&[1, 2, 30, 40])),
+                    (SPOT_LOCATION, card16s(&[5, (-6i16) as u16])),
+                ]),
+            ),
+            (STATUS, nested(&[(FOREGROUND, card32(0xff00ff))])),
+        ];
+        assert_values(
+            decode(&kinds, &items, &attr_names()),
+            &[
+                IcAttribute::FocusWindow(Window::new(7)),
+                IcAttribute::Preedit(AreaAttribute::Area(Rectangle {
+                    x: 1,
+                    y: 2,
+                    width: 30,
+                    height: 40,
+                })),
+                IcAttribute::Preedit(AreaAttribute::SpotLocation(Point { x: 5, y: -6 })),
+                IcAttribute::Status(AreaAttribute::Foreground(0xff00ff)),
+            ],
+        );
+    }
+
+    #[test]
+    fn nested_padding() {
+        let kinds = [
+            IcAttributeKind::Preedit(AreaAttributeKind::FontSet),
+            IcAttributeKind::Preedit(AreaAttributeKind::LineSpace),
+        ];
+        // the font set takes seven bytes and is followed by a byte of padding
+        let list = nested(&[(FONT_SET, font_set("fixed")), (LINE_SPACE, card32(18))]);
+        assert_eq!(list.len(), 4 + 8 + 4 + 4);
+        assert_values(
+            decode(&kinds, &[(PREEDIT, list)], &attr_names()),
+            &[
+                IcAttribute::Preedit(AreaAttribute::FontSet("fixed".to_owned())),
+                IcAttribute::Preedit(AreaAttribute::LineSpace(18)),
+            ],
+        );
+
+        // the padding of the last value may be missing
+        let mut list = nested(&[(LINE_SPACE, card32(18)), (FONT_SET, font_set("fixed"))]);
+        list.pop();
+        assert_values(
+            decode(&kinds, &[(PREEDIT, list)], &attr_names()),
+            &[
+                IcAttribute::Preedit(AreaAttribute::LineSpace(18)),
+                IcAttribute::Preedit(AreaAttribute::FontSet("fixed".to_owned())),
+            ],
+        );
+    }
+
+    #[test]
+    fn font_set_names() {
+        let kinds = [IcAttributeKind::Status(AreaAttributeKind::FontSet)];
+        let names = "-misc-fixed-*,-*-*-medium-r-normal--14-*";
+        let items = [(STATUS, nested(&[(FONT_SET, font_set(names))]))];
+        assert_values(
+            decode(&kinds, &items, &attr_names()),
+            &[IcAttribute::Status(AreaAttribute::FontSet(
+                names.to_owned(),
+            ))],
+        );
+
+        // the length exceeds the value
+        let mut value = card16s(&[10]);
+        value.extend_from_slice(b"fixed");
+        let items = [(STATUS, nested(&[(FONT_SET, value)]))];
+        assert_values(decode(&kinds, &items, &attr_names()), &[]);
+    }
+
+    #[test]
+    fn unknown_ids() {
+        let kinds = [
+            IcAttributeKind::InputStyle,
+            IcAttributeKind::Preedit(AreaAttributeKind::LineSpace),
+        ];
+        let items = [
+            (99, card32(1)),
+            // known, but not queried
+            (CLIENT_WINDOW, card32(2)),
+            (
+                PREEDIT,
+                nested(&[(98, card32(3)), (LINE_SPACE, card32(4)), (AREA, card32(5))]),
+            ),
+            (INPUT_STYLE, card32(InputStyle::PREEDIT_POSITION.bits())),
+        ];
+        assert_values(
+            decode(&kinds, &items, &attr_names()),
+            &[
+                IcAttribute::Preedit(AreaAttribute::LineSpace(4)),
+                IcAttribute::InputStyle(InputStyle::PREEDIT_POSITION),
+            ],
+        );
+    }
+
+    #[test]
+    fn truncated_values() {
+        let kinds = [
+            IcAttributeKind::ClientWindow,
+            IcAttributeKind::Preedit(AreaAttributeKind::Area),
+            IcAttributeKind::Preedit(AreaAttributeKind::SpotLocation),
+            IcAttributeKind::Status(AreaAttributeKind::Foreground),
+        ];
+        let mut status = nested(&[(FOREGROUND, card32(1))]);
+        status.truncate(6);
+        let items = [
+            (CLIENT_WINDOW, vec![1, 2]),
+            (
+                PREEDIT,
+                nested(&[
+                    (AREA, card16s(&[1, 2, 3])),
+                    (SPOT_LOCATION, card16s(&[4, 5])),
+                ]),
+            ),
+            (STATUS, status),
+        ];
+        assert_values(
+            decode(&kinds, &items, &attr_names()),
+            &[IcAttribute::Preedit(AreaAttribute::SpotLocation(Point {
+                x: 4,
+                y: 5,
+            }))],
+        );
+
+        // a nested list cut off within the header of a value
+        for len in 0..4 {
+            let items = [(PREEDIT, card16s(&[SPOT_LOCATION, 4])[..len].to_vec())];
+            assert_values(decode(&kinds, &items, &attr_names()), &[]);
+        }
+        assert_values(decode(&kinds, &[], &attr_names()), &[]);
+    }
+
+    #[test]
+    fn match_by_order() {
+        let kinds = [
+            IcAttributeKind::FocusWindow,
+            IcAttributeKind::Status(AreaAttributeKind::LineSpace),
+            IcAttributeKind::InputStyle,
+        ];
+        // the input style is requested before the nested list
+        let items = [
+            (0, card32(3)),
+            (0, card32(InputStyle::STATUS_AREA.bits())),
+            (0, nested(&[(0, card32(16))])),
+        ];
+        let no_names = HashMap::new();
+        assert_values(
+            decode(&kinds, &items, &no_names),
+            &[
+                IcAttribute::FocusWindow(Window::new(3)),
+                IcAttribute::InputStyle(InputStyle::STATUS_AREA),
+                IcAttribute::Status(AreaAttribute::LineSpace(16)),
+            ],
+        );
+
+        // without a value for every attribute, the values can not be matched
+        assert_values(decode(&kinds, &items[..2], &no_names), &[]);
+    }
+}